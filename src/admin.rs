@@ -0,0 +1,158 @@
+//! Access to a few Admin API endpoints, mostly read-only.
+//!
+//! The Admin API is signed the same way as the Auth API, but belongs to a
+//! separate Duo application: the client must be built with the ikey and skey
+//! of an Admin API application that has the "Grant read resource" permission.
+
+use std::{future::Future, sync::Arc};
+
+use futures_util::{stream, Stream};
+use reqwest::Method;
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use super::{
+    client::{DuoClient, DuoClientInner},
+    errors::Error,
+    request::Parameters,
+    response::Page,
+};
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AdminUser {
+    pub user_id: String,
+    pub username: String,
+    pub realname: Option<String>,
+    pub email: Option<String>,
+    /// `active`, `bypass`, `disabled`, `locked out` or `pending deletion`
+    pub status: Option<String>,
+    #[serde(default)]
+    pub is_enrolled: bool,
+    /// Unix timestamp of the last login, if the user ever logged in
+    pub last_login: Option<i64>,
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize)]
+pub struct AdminToken {
+    pub token_id: String,
+    pub serial: String,
+    /// `h6`, `h8`, `yk`, `d1` and so on, see Duo's Admin API documentation
+    pub r#type: String,
+    pub totp_step: Option<u64>,
+    /// The users the token is assigned to
+    #[serde(default)]
+    pub users: Vec<AdminUser>,
+}
+
+impl DuoClient {
+    /// Lists up to `limit` users, starting at `offset`. Duo caps `limit`
+    /// at 300.
+    pub fn list_users(
+        &self,
+        offset: u64,
+        limit: u64,
+    ) -> impl Future<Output = Result<Page<AdminUser>, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move { Self::request_admin_list(this, "/admin/v1/users", offset, limit).await }
+    }
+
+    /// Lists all users, `limit` at a time, fetching the next page once the
+    /// previous one was consumed.
+    pub fn user_pages(&self, limit: u64) -> impl Stream<Item = Result<Page<AdminUser>, Error>> {
+        Self::admin_pages(Arc::clone(&self.0), "/admin/v1/users", limit)
+    }
+
+    /// Lists up to `limit` hardware tokens, starting at `offset`. Duo caps
+    /// `limit` at 500.
+    pub fn list_tokens(
+        &self,
+        offset: u64,
+        limit: u64,
+    ) -> impl Future<Output = Result<Page<AdminToken>, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move { Self::request_admin_list(this, "/admin/v1/tokens", offset, limit).await }
+    }
+
+    /// Lists all hardware tokens, `limit` at a time, fetching the next page
+    /// once the previous one was consumed.
+    pub fn token_pages(&self, limit: u64) -> impl Stream<Item = Result<Page<AdminToken>, Error>> {
+        Self::admin_pages(Arc::clone(&self.0), "/admin/v1/tokens", limit)
+    }
+
+    /// Resynchronizes a hardware token that drifted too far from Duo's
+    /// counter, given three consecutive codes it generated, oldest first.
+    ///
+    /// The Auth API reports a passcode from an out-of-sync token like any
+    /// other wrong passcode, so there is no telling the two apart before
+    /// trying this. Needs the "Grant write resource" permission, and fails
+    /// with [`Error::TokenOutOfSync`] if Duo refuses the codes.
+    pub fn resync_token<T: Into<String>>(
+        &self,
+        token_id: T,
+        codes: [String; 3],
+    ) -> impl Future<Output = Result<(), Error>> {
+        let this = Arc::clone(&self.0);
+        let token_id = token_id.into();
+
+        async move {
+            let mut parameters = Parameters::default();
+            for (name, code) in ["code1", "code2", "code3"].into_iter().zip(codes) {
+                parameters.set(name, code);
+            }
+
+            let path = format!("/admin/v1/tokens/{}/resync", urlencoding::encode(&token_id));
+            let request =
+                Self::new_request(&this, Method::POST, path, parameters, this.timeouts.fast)
+                    .await?;
+            Self::send_request_json::<String>(&this, request)
+                .await
+                .map(drop)
+                .map_err(|err| err.into_token_out_of_sync(&token_id))
+        }
+    }
+
+    fn admin_pages<T>(
+        this: Arc<DuoClientInner>,
+        path: &'static str,
+        limit: u64,
+    ) -> impl Stream<Item = Result<Page<T>, Error>>
+    where
+        T: DeserializeOwned + std::fmt::Debug,
+    {
+        stream::try_unfold(Some(0), move |offset| {
+            let this = Arc::clone(&this);
+
+            async move {
+                let Some(offset) = offset else {
+                    return Ok(None);
+                };
+                let page = Self::request_admin_list(this, path, offset, limit).await?;
+                // Stop rather than loop should an offset not move forward
+                let next = page.next_offset().filter(|next| *next > offset);
+                Ok(Some((page, next)))
+            }
+        })
+    }
+
+    async fn request_admin_list<T>(
+        this: Arc<DuoClientInner>,
+        path: &str,
+        offset: u64,
+        limit: u64,
+    ) -> Result<Page<T>, Error>
+    where
+        T: DeserializeOwned + std::fmt::Debug,
+    {
+        let mut parameters = Parameters::default();
+        parameters.set("offset", offset.to_string());
+        parameters.set("limit", limit.to_string());
+
+        let request =
+            Self::new_request(&this, Method::GET, path, parameters, this.timeouts.fast).await?;
+        Self::send_request_raw(&this, request)
+            .await?
+            .body
+            .into_page()
+    }
+}
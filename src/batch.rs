@@ -0,0 +1,31 @@
+use super::errors::Error;
+
+#[derive(Debug)]
+pub struct BatchResult<T> {
+    /// Results in the same order as the requests
+    pub results: Vec<Result<T, Error>>,
+    pub summary: BatchSummary,
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub succeeded: usize,
+    /// Failures other than rate limiting
+    pub failed: usize,
+    pub rate_limited: usize,
+}
+
+impl<T> BatchResult<T> {
+    pub(crate) fn new(results: Vec<Result<T, Error>>) -> Self {
+        let mut summary = BatchSummary::default();
+        for result in &results {
+            match result {
+                Ok(_) => summary.succeeded += 1,
+                Err(err) if err.is_rate_limited() => summary.rate_limited += 1,
+                Err(_) => summary.failed += 1,
+            }
+        }
+
+        Self { results, summary }
+    }
+}
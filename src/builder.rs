@@ -0,0 +1,399 @@
+use std::{
+    net::SocketAddr,
+    sync::{atomic::AtomicI64, Arc, Mutex},
+    time::Duration,
+};
+
+use reqwest::{tls, Client, Request, Response, Url};
+
+use super::{
+    circuit::CircuitBreaker,
+    client::{DuoClient, DuoClientInner, Executor, SignedHeaderValue},
+    clock::{Clock, SystemClock},
+    credentials::{CredentialSource, Credentials},
+    errors::Error,
+    idempotency::IdempotentAuths,
+    limit::ConcurrencyLimiter,
+    poller::StatusPoller,
+    random::{RandomSource, SystemRandom},
+    request::{
+        Timeouts, WhenLimited, DEFAULT_CONTENT_TYPE, DEFAULT_USER_AGENT, SENSITIVE_PARAMETERS,
+    },
+    response::MAX_RESPONSE_BYTES,
+    transaction::{PollStrategy, TransactionRegistry, DEFAULT_POLL_JITTER},
+    types::{ApplyOptions, User},
+};
+
+pub struct DuoClientBuilder {
+    api_domain: String,
+    credentials: CredentialSource,
+
+    client: Option<Client>,
+    resolve: Vec<(String, SocketAddr)>,
+    http2: bool,
+    min_tls_version: tls::Version,
+    executor: Option<Executor>,
+    force_async: bool,
+    poll_strategy: PollStrategy,
+    poll_jitter: f64,
+    apply_options: ApplyOptions,
+    track_transactions: bool,
+    content_type: String,
+    user_agent: Option<String>,
+    circuit_breaker: Option<(u32, Duration)>,
+    concurrency_limit: Option<(usize, WhenLimited)>,
+    timeouts: Timeouts,
+    max_response_bytes: usize,
+    auto_sync_time: bool,
+    clock: Box<dyn Clock>,
+    random: Box<dyn RandomSource>,
+    signed_headers: Vec<(String, SignedHeaderValue)>,
+    signing_host: Option<String>,
+    sensitive_parameters: &'static [&'static str],
+    #[cfg(feature = "capture-bodies")]
+    capture_bodies: bool,
+}
+
+impl DuoClientBuilder {
+    pub(crate) fn new<D, I, S>(api_domain: D, ikey: I, skey: S) -> Self
+    where
+        D: Into<String>,
+        I: Into<String>,
+        S: Into<String>,
+    {
+        Self::with_credentials(
+            api_domain,
+            CredentialSource::fixed(Credentials::new(ikey, skey)),
+        )
+    }
+
+    pub(crate) fn with_credentials<D: Into<String>>(
+        api_domain: D,
+        credentials: CredentialSource,
+    ) -> Self {
+        Self {
+            api_domain: api_domain.into(),
+            credentials,
+            client: None,
+            resolve: Vec::new(),
+            http2: false,
+            min_tls_version: tls::Version::TLS_1_2,
+            executor: None,
+            force_async: true,
+            poll_strategy: PollStrategy::default(),
+            poll_jitter: DEFAULT_POLL_JITTER,
+            apply_options: ApplyOptions::default(),
+            track_transactions: false,
+            content_type: DEFAULT_CONTENT_TYPE.into(),
+            user_agent: Some(DEFAULT_USER_AGENT.into()),
+            circuit_breaker: None,
+            concurrency_limit: None,
+            timeouts: Timeouts::default(),
+            max_response_bytes: MAX_RESPONSE_BYTES,
+            auto_sync_time: false,
+            clock: Box::new(SystemClock),
+            random: Box::new(SystemRandom),
+            signed_headers: Vec::new(),
+            signing_host: None,
+            sensitive_parameters: SENSITIVE_PARAMETERS,
+            #[cfg(feature = "capture-bodies")]
+            capture_bodies: false,
+        }
+    }
+
+    /// Uses the given client instead of constructing one. Options affecting
+    /// the HTTP connection are ignored when a client is supplied.
+    pub fn client<C: Into<Client>>(mut self, client: C) -> Self {
+        self.client = Some(client.into());
+        self
+    }
+
+    /// Connects to `addr` whenever `domain` would be resolved through DNS.
+    ///
+    /// This only changes where the connection goes; requests are still signed
+    /// with the host of the API domain, not with the pinned address.
+    pub fn resolve<D: Into<String>>(mut self, domain: D, addr: SocketAddr) -> Self {
+        self.resolve.push((domain.into(), addr));
+        self
+    }
+
+    /// Speaks HTTP/2 from the start instead of negotiating the protocol, so
+    /// concurrent requests are multiplexed over a single connection. Signing
+    /// only covers the request contents and is not affected.
+    pub fn http2(mut self, http2: bool) -> Self {
+        self.http2 = http2;
+        self
+    }
+
+    /// Refuses connections below this TLS version, TLS 1.2 by default. Ignored
+    /// when a custom client is supplied, which then has to enforce it itself.
+    ///
+    /// The native-tls backend the client is built with cannot require TLS 1.3,
+    /// so [`build`](Self::build) fails with [`Error::InvalidParameter`] for
+    /// [`tls::Version::TLS_1_3`]. Supply a [`client`](Self::client) built with
+    /// reqwest's rustls backend to require it.
+    pub fn min_tls_version(mut self, min_tls_version: tls::Version) -> Self {
+        self.min_tls_version = min_tls_version;
+        self
+    }
+
+    /// Hands every request to `executor` instead of sending it over HTTP,
+    /// which allows exercising the client against canned responses in tests.
+    pub fn executor<F>(mut self, executor: F) -> Self
+    where
+        F: FnMut(Request) -> Result<Response, reqwest::Error> + Send + 'static,
+    {
+        self.executor = Some(Box::new(executor));
+        self
+    }
+
+    /// Whether auth requests are sent with `async=1`, which is the default.
+    ///
+    /// Turning this off is only useful against backends that do not implement
+    /// Duo's asynchronous transactions. Auths then answer with their result,
+    /// which only [`DuoClient::auth_start`] and the methods that wait for the
+    /// result can return.
+    pub fn force_async(mut self, force_async: bool) -> Self {
+        self.force_async = force_async;
+        self
+    }
+
+    /// Redacts these parameters instead of [`SENSITIVE_PARAMETERS`] in
+    /// captured bodies and in parameter previews such as
+    /// [`DuoClient::auth_parameters`].
+    pub fn sensitive_parameters(mut self, sensitive_parameters: &'static [&'static str]) -> Self {
+        self.sensitive_parameters = sensitive_parameters;
+        self
+    }
+
+    /// Attaches the request parameters and the raw response body to
+    /// [`Error::ApiRequestFailed`]. Secret parameters such as passcodes are
+    /// redacted, see [`sensitive_parameters`](Self::sensitive_parameters),
+    /// and the Authorization header is never captured.
+    #[cfg(feature = "capture-bodies")]
+    pub fn capture_bodies(mut self, capture_bodies: bool) -> Self {
+        self.capture_bodies = capture_bodies;
+        self
+    }
+
+    pub fn poll_strategy(mut self, poll_strategy: PollStrategy) -> Self {
+        self.poll_strategy = poll_strategy;
+        self
+    }
+
+    /// Randomly lengthens or shortens each delay between polls by up to this
+    /// fraction, 0.1 by default, so that concurrent waits spread out. Zero
+    /// disables jitter.
+    pub fn poll_jitter(mut self, fraction: f64) -> Self {
+        self.poll_jitter = fraction;
+        self
+    }
+
+    /// Rewrites usernames before they are sent, e.g. to turn `DOMAIN\user`
+    /// or `user@domain` into the form Duo is configured with. User ids are
+    /// sent unchanged.
+    pub fn normalize_username<F>(mut self, normalize: F) -> Self
+    where
+        F: Fn(&str) -> String + Send + Sync + 'static,
+    {
+        self.apply_options.normalize_username = Some(Box::new(normalize));
+        self
+    }
+
+    /// Keeps a registry of live [`AuthTransaction`](crate::AuthTransaction)
+    /// handles, exposed through [`DuoClient::in_flight`].
+    pub fn track_transactions(mut self, track_transactions: bool) -> Self {
+        self.track_transactions = track_transactions;
+        self
+    }
+
+    /// Derives `display_username` for push requests that do not set one, so
+    /// e.g. a friendly name is shown instead of an opaque user id.
+    pub fn display_username<F>(mut self, display_username: F) -> Self
+    where
+        F: Fn(&User) -> Option<String> + Send + Sync + 'static,
+    {
+        self.apply_options.display_username = Some(Box::new(display_username));
+        self
+    }
+
+    /// Sets the User-Agent, which defaults to [`DEFAULT_USER_AGENT`], or sends
+    /// none at all when `None`. With a custom client, `None` leaves it to the
+    /// client's own default, if it has one.
+    pub fn user_agent(mut self, user_agent: Option<String>) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Overrides the Content-Type of request bodies, which defaults to
+    /// [`DEFAULT_CONTENT_TYPE`]. Some gateways expect a charset to be
+    /// appended. The content type is not signed.
+    pub fn content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    /// Fails requests with [`Error::CircuitOpen`] for `open_duration` once
+    /// `failure_threshold` consecutive requests could not reach Duo or got a
+    /// 5xx back. Afterwards a single trial request is let through, which
+    /// closes the breaker again if it succeeds.
+    pub fn circuit_breaker(mut self, failure_threshold: u32, open_duration: Duration) -> Self {
+        self.circuit_breaker = Some((failure_threshold, open_duration));
+        self
+    }
+
+    /// Allows at most `limit` requests in flight at once across all calls on
+    /// this client and its clones. Further requests wait for a free slot or
+    /// fail, depending on `when_limited`. Unlimited by default.
+    pub fn concurrency_limit(mut self, limit: usize, when_limited: WhenLimited) -> Self {
+        self.concurrency_limit = Some((limit, when_limited));
+        self
+    }
+
+    /// Sets the per-request timeouts, see [`Timeouts`] for the defaults.
+    /// These also apply when a custom client is supplied.
+    pub fn timeouts(mut self, timeouts: Timeouts) -> Self {
+        self.timeouts = timeouts;
+        self
+    }
+
+    /// Fails requests whose response body is larger than this, see
+    /// [`MAX_RESPONSE_BYTES`] for the default.
+    pub fn max_response_bytes(mut self, max_response_bytes: usize) -> Self {
+        self.max_response_bytes = max_response_bytes;
+        self
+    }
+
+    /// Keeps the clock offset used for signing up to date with the `Date`
+    /// header of every response. See also [`DuoClient::sync_time`].
+    pub fn auto_sync_time(mut self, auto_sync_time: bool) -> Self {
+        self.auto_sync_time = auto_sync_time;
+        self
+    }
+
+    /// Replaces the system clock, e.g. with a fake one in tests.
+    pub fn clock<C: Clock + 'static>(mut self, clock: C) -> Self {
+        self.clock = Box::new(clock);
+        self
+    }
+
+    /// Replaces the randomness behind poll jitter, e.g. with a
+    /// [`SeededRandom`](crate::random::SeededRandom) to make delays
+    /// reproducible in tests.
+    pub fn random_source<R: RandomSource + 'static>(mut self, random: R) -> Self {
+        self.random = Box::new(random);
+        self
+    }
+
+    /// Sends the header `name` with every request and covers it by the
+    /// signature, for backends that sign more than the Date header. `value`
+    /// is called for each request, e.g. to generate a request id. Duo's Auth
+    /// API does not sign additional headers, so this is off by default.
+    pub fn signed_header<N, F>(mut self, name: N, value: F) -> Self
+    where
+        N: Into<String>,
+        F: Fn() -> String + Send + Sync + 'static,
+    {
+        self.signed_headers.push((name.into(), Box::new(value)));
+        self
+    }
+
+    /// Signs requests for `host` instead of the host of the API domain. Duo
+    /// checks signatures against the host it was reached at, so this is only
+    /// of use with a custom client or executor that presents requests to Duo
+    /// under `host`, e.g. when the API domain is a synthetic host such as
+    /// `http://duo.internal` routed over a unix socket.
+    ///
+    /// The API domain still needs a host: reqwest only builds requests for
+    /// http and https URLs, which always have one.
+    pub fn signing_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.signing_host = Some(host.into());
+        self
+    }
+
+    pub fn build(self) -> Result<DuoClient, Error> {
+        let api_domain = self.api_domain;
+
+        let base_url = match Url::parse(&api_domain) {
+            Ok(url) => url,
+            Err(err) => {
+                return Err(Error::InvalidApiDomain {
+                    domain: api_domain,
+                    cause: err.into(),
+                })
+            }
+        };
+
+        // Fail fast when there's no domain
+        if base_url.host_str().is_none() {
+            return Err(Error::InvalidApiDomain {
+                domain: api_domain,
+                cause: "no domain in url".into(),
+            });
+        }
+
+        let client = match self.client {
+            Some(client) => client,
+            None => {
+                if self.min_tls_version == tls::Version::TLS_1_3 {
+                    return Err(Error::InvalidParameter {
+                        name: "min_tls_version",
+                        reason:
+                            "TLS 1.3 cannot be required with native-tls, supply a rustls client"
+                                .into(),
+                    });
+                }
+
+                let mut builder = reqwest::Client::builder().min_tls_version(self.min_tls_version);
+                if let Some(user_agent) = &self.user_agent {
+                    builder = builder.user_agent(user_agent);
+                }
+
+                for (domain, addr) in &self.resolve {
+                    builder = builder.resolve(domain, *addr);
+                }
+
+                if self.http2 {
+                    builder = builder.http2_prior_knowledge().http2_adaptive_window(true);
+                }
+
+                builder.build().map_err(Error::unspecified)?
+            }
+        };
+
+        Ok(DuoClient(Arc::new(DuoClientInner {
+            base_url,
+            credentials: self.credentials,
+            client,
+            executor: self.executor.map(Mutex::new),
+            force_async: self.force_async,
+            poll_strategy: self.poll_strategy,
+            poll_jitter: self.poll_jitter,
+            apply_options: self.apply_options,
+            registry: self.track_transactions.then(TransactionRegistry::default),
+            content_type: self.content_type,
+            idempotent_auths: IdempotentAuths::default(),
+            poller: StatusPoller::default(),
+            circuit_breaker: self
+                .circuit_breaker
+                .map(|(failure_threshold, open_duration)| {
+                    CircuitBreaker::new(failure_threshold, open_duration)
+                }),
+            concurrency_limiter: self
+                .concurrency_limit
+                .map(|(limit, when_limited)| ConcurrencyLimiter::new(limit, when_limited)),
+            timeouts: self.timeouts,
+            max_response_bytes: self.max_response_bytes,
+            clock_offset: AtomicI64::new(0),
+            auto_sync_time: self.auto_sync_time,
+            clock: self.clock,
+            random: self.random,
+            signed_headers: self.signed_headers,
+            signing_host: self.signing_host,
+            user_agent: self.user_agent,
+            sensitive_parameters: self.sensitive_parameters,
+            #[cfg(feature = "capture-bodies")]
+            capture_bodies: self.capture_bodies,
+        })))
+    }
+}
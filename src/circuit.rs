@@ -0,0 +1,80 @@
+use std::{
+    sync::{Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use super::errors::Error;
+
+enum State {
+    Closed {
+        failures: u32,
+    },
+    Open {
+        until: Instant,
+    },
+    /// A trial request was let through after the breaker was open
+    HalfOpen {
+        since: Instant,
+    },
+}
+
+pub(crate) struct CircuitBreaker {
+    failure_threshold: u32,
+    open_duration: Duration,
+    state: Mutex<State>,
+}
+
+impl CircuitBreaker {
+    pub(crate) fn new(failure_threshold: u32, open_duration: Duration) -> Self {
+        Self {
+            failure_threshold: failure_threshold.max(1),
+            open_duration,
+            state: Mutex::new(State::Closed { failures: 0 }),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Fails with [`Error::CircuitOpen`] unless a request may be sent now.
+    pub(crate) fn acquire(&self) -> Result<(), Error> {
+        let mut state = self.state();
+        let now = Instant::now();
+
+        match *state {
+            State::Closed { .. } => Ok(()),
+            State::Open { until } if now < until => Err(Error::CircuitOpen {
+                retry_after: until - now,
+            }),
+            // A trial that never reported back (e.g. a dropped future) must
+            // not keep the breaker half-open forever
+            State::HalfOpen { since } if now < since + self.open_duration => {
+                Err(Error::CircuitOpen {
+                    retry_after: since + self.open_duration - now,
+                })
+            }
+            State::Open { .. } | State::HalfOpen { .. } => {
+                *state = State::HalfOpen { since: now };
+                Ok(())
+            }
+        }
+    }
+
+    pub(crate) fn record(&self, success: bool) {
+        let mut state = self.state();
+
+        *state = match (&*state, success) {
+            (_, true) => State::Closed { failures: 0 },
+            (State::Closed { failures }, false) if failures + 1 < self.failure_threshold => {
+                State::Closed {
+                    failures: failures + 1,
+                }
+            }
+            (State::Open { until }, false) => State::Open { until: *until },
+            (_, false) => State::Open {
+                until: Instant::now() + self.open_duration,
+            },
+        };
+    }
+}
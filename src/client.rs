@@ -1,46 +1,219 @@
-use std::{future::Future, sync::Arc, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    future::Future,
+    sync::{
+        atomic::{AtomicI64, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
 
-use reqwest::{Client, Method, Request, Url};
+use chrono::{DateTime, TimeZone, Utc};
+use futures_util::{
+    future,
+    stream::{self, FuturesUnordered},
+    Stream, StreamExt,
+};
+use reqwest::{header::DATE, Client, Method, Request, Response, StatusCode, Url};
 use serde::{de::DeserializeOwned, Deserialize};
+use tokio::sync::SemaphorePermit;
 
 use super::{
+    batch::BatchResult,
+    builder::DuoClientBuilder,
+    circuit::CircuitBreaker,
+    clock::Clock,
+    credentials::{CredentialProvider, CredentialSource, Credentials},
+    enrollment::EnrollSession,
     errors::Error,
-    request::{DuoRequest, Parameters},
-    response::DuoResponse,
+    idempotency::{IdempotentAuths, Slot},
+    limit::ConcurrencyLimiter,
+    poller::StatusPoller,
+    random::RandomSource,
+    region::DuoRegion,
+    request::{DuoRequest, Parameters, RequestContext, Timeouts},
+    response::{self, DuoResponse, RawResponse, ResponseMeta},
+    transaction::{AuthTransaction, PollStrategy, TransactionRegistry, TransactionSnapshot},
     types::PreauthResponse,
     types::{
-        AuthRequest, AuthStatusResponse, EnrollResponse, EnrollStatusResponse, PreauthRequest,
+        select_device, ApplyOptions, AuthOutcome, AuthRequest, AuthRequestFactor, AuthStart,
+        AuthStatus, AuthStatusResponse, AuthenticateOptions, Device, DeviceCapability,
+        DeviceSelector, EnrollResponse, EnrollStatusResponse, Failmode, HealthStatus,
+        PreauthOrEnroll, PreauthOutcome, PreauthRequest, SmsSendResult, User,
     },
     StdError,
 };
 
-pub struct DuoClient(Arc<DuoClientInner>);
+#[cfg(feature = "otel")]
+use super::otel;
+#[cfg(feature = "capture-bodies")]
+use super::{errors::CapturedBodies, request};
 
-struct DuoClientInner {
-    base_url: Url,
-    ikey: String,
-    skey: String,
+/// Clones are cheap and share all state, such as the connection pool, the
+/// cached credentials, the circuit breaker and the clock offset.
+#[derive(Clone)]
+pub struct DuoClient(pub(crate) Arc<DuoClientInner>);
+
+pub(crate) struct DuoClientInner {
+    pub(crate) base_url: Url,
+    pub(crate) credentials: CredentialSource,
+
+    pub(crate) client: reqwest::Client,
+    pub(crate) executor: Option<Mutex<Executor>>,
+    pub(crate) force_async: bool,
+    pub(crate) poll_strategy: PollStrategy,
+    pub(crate) poll_jitter: f64,
+    pub(crate) apply_options: ApplyOptions,
+    pub(crate) registry: Option<TransactionRegistry>,
+    pub(crate) content_type: String,
+    pub(crate) idempotent_auths: IdempotentAuths,
+    pub(crate) poller: StatusPoller,
+    pub(crate) circuit_breaker: Option<CircuitBreaker>,
+    pub(crate) concurrency_limiter: Option<ConcurrencyLimiter>,
+    pub(crate) timeouts: Timeouts,
+    pub(crate) max_response_bytes: usize,
+    /// Server time minus local time, in milliseconds
+    pub(crate) clock_offset: AtomicI64,
+    pub(crate) clock: Box<dyn Clock>,
+    pub(crate) random: Box<dyn RandomSource>,
+    pub(crate) signed_headers: Vec<(String, SignedHeaderValue)>,
+    pub(crate) signing_host: Option<String>,
+    pub(crate) user_agent: Option<String>,
+    pub(crate) auto_sync_time: bool,
+    pub(crate) sensitive_parameters: &'static [&'static str],
+    #[cfg(feature = "capture-bodies")]
+    pub(crate) capture_bodies: bool,
+}
 
-    client: reqwest::Client,
+tokio::task_local! {
+    static DEADLINE: Instant;
+}
+
+pub type Executor = Box<dyn FnMut(Request) -> Result<Response, reqwest::Error> + Send>;
+pub type SignedHeaderValue = Box<dyn Fn() -> String + Send + Sync>;
+
+impl DuoClientInner {
+    pub(crate) fn now(&self) -> DateTime<Utc> {
+        self.clock.now() + chrono::Duration::milliseconds(self.clock_offset.load(Ordering::Relaxed))
+    }
+
+    fn set_server_time(&self, server_time: DateTime<Utc>) -> chrono::Duration {
+        let offset = server_time.signed_duration_since(self.clock.now());
+        self.clock_offset
+            .store(offset.num_milliseconds(), Ordering::Relaxed);
+        offset
+    }
+
+    /// Holds a slot of the concurrency limit, if any, until the response has
+    /// been read.
+    async fn acquire_slot(&self) -> Result<Option<SemaphorePermit<'_>>, Error> {
+        match &self.concurrency_limiter {
+            Some(limiter) => limiter.acquire().await.map(Some),
+            None => Ok(None),
+        }
+    }
+
+    async fn execute(&self, request: Request) -> Result<Response, reqwest::Error> {
+        match &self.executor {
+            Some(executor) => (executor.lock().unwrap_or_else(PoisonError::into_inner))(request),
+            None => self.client.execute(request).await,
+        }
+    }
 }
 
 impl DuoClient {
+    /// Runs `fut`, typically a call on a client, within `deadline`. Every
+    /// request it sends has its timeout shortened to the time remaining, and
+    /// waiting in between is cut short as well, failing with
+    /// [`Error::DeadlineExceeded`].
+    ///
+    /// Requests that run on a separate task, such as those of
+    /// [`DuoClient::auth_idempotent`] or the `auth_status` requests of the
+    /// poller shared by [`DuoClient::auth_wait`] callers, are not shortened,
+    /// but the caller still stops waiting for them at the deadline.
+    pub fn with_deadline<F, T>(deadline: Instant, fut: F) -> impl Future<Output = Result<T, Error>>
+    where
+        F: Future<Output = Result<T, Error>>,
+    {
+        DEADLINE.scope(deadline, async move {
+            let result = tokio::time::timeout_at(deadline.into(), fut).await;
+            match result {
+                Ok(Err(err)) if Instant::now() >= deadline && err.is_timeout() => {
+                    Err(Error::DeadlineExceeded)
+                }
+                Ok(result) => result,
+                Err(_) => Err(Error::DeadlineExceeded),
+            }
+        })
+    }
+
+    pub fn builder<D, I, S>(api_domain: D, ikey: I, skey: S) -> DuoClientBuilder
+    where
+        D: Into<String>,
+        I: Into<String>,
+        S: Into<String>,
+    {
+        DuoClientBuilder::new(api_domain, ikey, skey)
+    }
+
+    /// Like [`DuoClient::builder`], but obtains the keys from `provider`
+    /// whenever the previously obtained ones are older than `ttl`.
+    pub fn builder_with_provider<D, P>(
+        api_domain: D,
+        provider: P,
+        ttl: Duration,
+    ) -> DuoClientBuilder
+    where
+        D: Into<String>,
+        P: CredentialProvider + 'static,
+    {
+        DuoClientBuilder::with_credentials(api_domain, CredentialSource::provider(provider, ttl))
+    }
+
     pub fn new<D, I, S>(api_domain: D, ikey: I, skey: S) -> Result<DuoClient, Error>
     where
         D: Into<String>,
         I: Into<String>,
         S: Into<String>,
     {
-        let client = reqwest::Client::builder()
-            .user_agent(concat!(
-                env!("CARGO_PKG_NAME"),
-                "/",
-                env!("CARGO_PKG_VERSION")
-            ))
-            .build()
-            .map_err(Error::unspecified)?;
+        Self::builder(api_domain, ikey, skey).build()
+    }
 
-        Self::new_with_client(client, api_domain, ikey, skey)
+    /// Like [`DuoClient::new`], with the API domain built from the account's
+    /// `subdomain` in `region`, see [`DuoRegion::api_domain`].
+    pub fn for_region<I, S>(
+        region: DuoRegion,
+        subdomain: &str,
+        ikey: I,
+        skey: S,
+    ) -> Result<DuoClient, Error>
+    where
+        I: Into<String>,
+        S: Into<String>,
+    {
+        Self::new(region.api_domain(subdomain)?, ikey, skey)
+    }
+
+    /// Like [`DuoClient::new`], but also calls `check` to make sure Duo
+    /// accepts the keys, failing with [`Error::InvalidCredentials`] if not.
+    /// Useful to fail at startup rather than on the first login.
+    pub fn connect<D, I, S>(
+        api_domain: D,
+        ikey: I,
+        skey: S,
+    ) -> impl Future<Output = Result<DuoClient, Error>>
+    where
+        D: Into<String>,
+        I: Into<String>,
+        S: Into<String>,
+    {
+        let client = Self::new(api_domain, ikey, skey);
+
+        async move {
+            let client = client?;
+            client.check().await?;
+            Ok(client)
+        }
     }
 
     pub fn new_with_client<C, D, I, S>(
@@ -55,89 +228,376 @@ impl DuoClient {
         I: Into<String>,
         S: Into<String>,
     {
-        let api_domain = api_domain.into();
-
-        let base_url = match Url::parse(&api_domain) {
-            Ok(url) => url,
-            Err(err) => {
-                return Err(Error::InvalidApiDomain {
-                    domain: api_domain,
-                    cause: err.into(),
-                })
-            }
-        };
+        Self::builder(api_domain, ikey, skey).client(client).build()
+    }
 
-        // Fail fast when there's no domain
-        let _ = base_url
-            .host_str()
-            .ok_or_else(|| Error::InvalidApiDomain {
-                domain: api_domain,
-                cause: "no domain in url".into(),
-            })?
-            .to_string();
+    /// Starts an auth and returns its txid.
+    ///
+    /// The push, call or SMS may already have been dispatched when the
+    /// returned future is dropped, e.g. by a timeout, even though no txid was
+    /// received. Use [`DuoClient::auth_idempotent`] where retrying must not
+    /// notify the user twice.
+    pub fn auth(&self, data: AuthRequest) -> impl Future<Output = Result<String, Error>> {
+        let this = Arc::clone(&self.0);
 
-        Ok(DuoClient(Arc::new(DuoClientInner {
-            base_url,
-            ikey: ikey.into(),
-            skey: skey.into(),
-            client: client.into(),
-        })))
+        async move {
+            Self::request_auth(this, data, &RequestContext::default())
+                .await?
+                .into_txid()
+        }
     }
 
-    pub fn auth(&self, data: AuthRequest) -> impl Future<Output = Result<String, Error>> {
+    /// Starts an auth and returns its txid, or its result if Duo decided
+    /// right away without a transaction to poll.
+    pub fn auth_start(&self, data: AuthRequest) -> impl Future<Output = Result<AuthStart, Error>> {
         let this = Arc::clone(&self.0);
 
-        async move { Self::request_auth(this, data).await }
+        async move { Self::request_auth(this, data, &RequestContext::default()).await }
     }
 
-    pub fn auth_status<S: Into<String>>(
+    /// Like [`DuoClient::auth`], but calls sharing `key` within a few minutes
+    /// yield the txid of the first call instead of starting another auth.
+    ///
+    /// Duo has no idempotency support of its own, so this is enforced within
+    /// this client only. The request runs on a separate task and completes
+    /// even if the returned future is dropped, so retrying after a timeout
+    /// picks up its txid. A failed auth is forgotten and may be retried.
+    pub fn auth_idempotent<K: Into<String>>(
         &self,
-        tx_id: S,
-    ) -> impl Future<Output = Result<AuthStatusResponse, Error>> {
+        key: K,
+        data: AuthRequest,
+    ) -> impl Future<Output = Result<String, Error>> {
         let this = Arc::clone(&self.0);
+        let key = key.into();
 
         async move {
-            let txid: String = tx_id.into();
-            Self::request_auth_status(this, &txid).await
+            let mut receiver = match this.idempotent_auths.slot(&key) {
+                Slot::Existing(receiver) => receiver,
+                Slot::New(sender, receiver) => {
+                    tokio::spawn(async move {
+                        let result =
+                            Self::request_auth(this.clone(), data, &RequestContext::default())
+                                .await
+                                .and_then(AuthStart::into_txid);
+                        if result.is_err() {
+                            this.idempotent_auths.forget(&key);
+                        }
+                        let _ = sender.send(Some(result.map_err(Arc::new)));
+                    });
+                    receiver
+                }
+            };
+
+            loop {
+                if let Some(result) = receiver.borrow().as_ref() {
+                    return result.clone().map_err(Error::Shared);
+                }
+                receiver
+                    .changed()
+                    .await
+                    .map_err(|_| Error::unspecified("idempotent auth was aborted"))?;
+            }
         }
     }
 
-    pub fn auth_wait(&self, data: AuthRequest) -> impl Future<Output = Result<bool, StdError>> {
+    /// The parameters this client sends `data` with, with secrets redacted.
+    /// Unlike [`AuthRequest::parameters`], this applies the username
+    /// normalizer and display username set on the builder, and includes
+    /// `async` if the client forces it.
+    pub fn auth_parameters(&self, data: &AuthRequest) -> Result<BTreeMap<String, String>, Error> {
+        let mut parameters = Parameters::default();
+        if self.0.force_async {
+            parameters.set("async", "1");
+        }
+        data.clone().apply(&mut parameters, &self.0.apply_options)?;
+        Ok(parameters.redacted_with(self.0.sensitive_parameters))
+    }
+
+    pub fn auth_with_context(
+        &self,
+        data: AuthRequest,
+        context: RequestContext,
+    ) -> impl Future<Output = Result<String, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move { Self::request_auth(this, data, &context).await?.into_txid() }
+    }
+
+    pub fn begin_auth(
+        &self,
+        data: AuthRequest,
+    ) -> impl Future<Output = Result<AuthTransaction, Error>> {
         let this = Arc::clone(&self.0);
 
         async move {
-            let txid = Self::request_auth(this.clone(), data).await?;
-            let mut status: Option<bool>;
+            let txid = Self::request_auth(this.clone(), data, &RequestContext::default())
+                .await?
+                .into_txid()?;
+            Ok(AuthTransaction::new(this, txid))
+        }
+    }
 
-            loop {
-                status = Self::request_auth_status(this.clone(), &txid)
+    /// Sends a new batch of SMS passcodes to `device`. Duo reports this as a
+    /// denied auth with status `sent`, which is turned into an error if the
+    /// passcodes were not sent.
+    pub fn send_sms_passcodes<D: Into<DeviceSelector>>(
+        &self,
+        user: User,
+        device: D,
+    ) -> impl Future<Output = Result<SmsSendResult, Error>> {
+        let this = Arc::clone(&self.0);
+        let data = AuthRequest::new(
+            user,
+            AuthRequestFactor::Sms {
+                device: device.into(),
+            },
+        );
+
+        async move {
+            let status = match Self::request_auth(this.clone(), data, &RequestContext::default())
+                .await?
+            {
+                AuthStart::Pending(txid) => AuthTransaction::new(this, txid).wait_full().await?,
+                AuthStart::Immediate(status) => status,
+            };
+
+            match status.status {
+                AuthStatus::Sent => Ok(SmsSendResult::from_status_msg(status.status_msg)),
+                _ => Err(Error::unspecified(format!(
+                    "SMS passcodes not sent: {}",
+                    status.status_msg
+                ))),
+            }
+        }
+    }
+
+    /// Runs the whole flow: preauth, then, if a second factor is needed,
+    /// auth with the most preferred capable device and wait for the result.
+    pub fn authenticate(
+        &self,
+        user: User,
+        options: AuthenticateOptions,
+    ) -> impl Future<Output = Result<AuthOutcome, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let preauth = PreauthRequest::new(user.clone());
+            let devices =
+                match Self::request_preauth(this.clone(), preauth, &RequestContext::default())
                     .await?
-                    .ready();
-                match status {
-                    None => tokio::time::sleep(Duration::from_secs(2)).await,
-                    Some(v) => return Ok(v),
+                {
+                    response if response.is_locked_out() => {
+                        return Ok(AuthOutcome::LockedOut(None))
+                    }
+                    PreauthResponse::Auth { devices } => devices,
+                    PreauthResponse::Allow { status_msg, .. } => {
+                        return Ok(AuthOutcome::Bypassed { status_msg })
+                    }
+                    PreauthResponse::Deny => return Ok(AuthOutcome::Denied(None)),
+                    PreauthResponse::Enroll { enroll_portal_url } => {
+                        return Ok(AuthOutcome::NeedsEnrollment { enroll_portal_url })
+                    }
+                };
+
+            let usable = [
+                DeviceCapability::Push,
+                DeviceCapability::Phone,
+                DeviceCapability::Auto,
+            ];
+            let preference: Vec<DeviceCapability> = options
+                .preference
+                .into_iter()
+                .filter(|capability| usable.contains(capability))
+                .collect();
+
+            let request = match select_device(&devices, &preference) {
+                Some((device, DeviceCapability::Push)) => device.push_request(&user),
+                Some((device, DeviceCapability::Phone)) => device.phone_request(&user),
+                Some((device, _)) => AuthRequest::new(
+                    user,
+                    AuthRequestFactor::Auto {
+                        device: Some(device.device.clone()),
+                        r#type: None,
+                        display_username: None,
+                        push_info: None,
+                    },
+                ),
+                None => return Ok(AuthOutcome::NoCapableDevice),
+            };
+
+            let poll_strategy = options.poll_strategy.unwrap_or(this.poll_strategy);
+            let status = match Self::request_auth(this.clone(), request, &RequestContext::default())
+                .await?
+            {
+                AuthStart::Pending(txid) => {
+                    AuthTransaction::new(this, txid)
+                        .wait_with(poll_strategy)
+                        .await?
                 }
-            }
+                AuthStart::Immediate(status) => status,
+            };
+
+            Ok(match status.ready() {
+                _ if status.is_locked_out() => AuthOutcome::LockedOut(Some(status)),
+                Some(true) => AuthOutcome::Allowed(status),
+                _ => AuthOutcome::Denied(Some(status)),
+            })
         }
     }
 
-    pub fn check(&self) -> impl Future<Output = Result<u64, Error>> {
+    /// Pushes to every push-capable device in `devices` at once and returns
+    /// the first approval. If no push is approved, returns the status that
+    /// finished last, or the last error if none finished.
+    ///
+    /// The pushes are sent and polled like any other auth, within the
+    /// concurrency limit and through the shared poller, which retries
+    /// rate-limited polls. Once a push is approved the others are no longer
+    /// polled, but Duo cannot cancel them, so they stay on the other devices
+    /// until they time out.
+    pub fn auth_race(
+        &self,
+        user: User,
+        devices: Vec<&Device>,
+    ) -> impl Future<Output = Result<AuthStatusResponse, Error>> {
         let this = Arc::clone(&self.0);
+        let requests: Vec<AuthRequest> = devices
+            .into_iter()
+            .filter(|device| device.has_capability(DeviceCapability::Push))
+            .map(|device| device.push_request(&user))
+            .collect();
 
         async move {
-            #[derive(Deserialize, Debug)]
-            struct CheckResponse {
-                time: u64,
+            if requests.is_empty() {
+                return Err(Error::InvalidParameter {
+                    name: "devices",
+                    reason: "no device supports push".into(),
+                });
+            }
+
+            let context = RequestContext::default();
+            let starts = future::join_all(
+                requests
+                    .into_iter()
+                    .map(|request| Self::request_auth(this.clone(), request, &context)),
+            )
+            .await;
+
+            let mut last_status = None;
+            let mut last_error = None;
+            let mut waits = FuturesUnordered::new();
+            for start in starts {
+                match start {
+                    Ok(AuthStart::Pending(txid)) => {
+                        waits.push(AuthTransaction::new(this.clone(), txid).wait_shared())
+                    }
+                    Ok(AuthStart::Immediate(status)) if status.ready() == Some(true) => {
+                        return Ok(status)
+                    }
+                    Ok(AuthStart::Immediate(status)) => last_status = Some(status),
+                    Err(err) => last_error = Some(err),
+                }
+            }
+
+            // Dropping the remaining waits stops polling their transactions
+            while let Some(result) = waits.next().await {
+                match result {
+                    Ok(status) if status.ready() == Some(true) => return Ok(status),
+                    Ok(status) => last_status = Some(status),
+                    Err(err) => last_error = Some(err),
+                }
             }
 
+            last_status.ok_or_else(|| {
+                last_error.unwrap_or_else(|| Error::unspecified("no push was started"))
+            })
+        }
+    }
+
+    pub fn auth_status<S: Into<String>>(
+        &self,
+        tx_id: S,
+    ) -> impl Future<Output = Result<AuthStatusResponse, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let txid: String = tx_id.into();
+            Self::request_auth_status(this, &txid, &RequestContext::default()).await
+        }
+    }
+
+    /// Like [`DuoClient::auth_status`], with the correlation header and
+    /// unsigned query parameters of `context`.
+    pub fn auth_status_with_context<S: Into<String>>(
+        &self,
+        tx_id: S,
+        context: RequestContext,
+    ) -> impl Future<Output = Result<AuthStatusResponse, Error>> {
+        let this = Arc::clone(&self.0);
+        let txid = tx_id.into();
+
+        async move { Self::request_auth_status(this, &txid, &context).await }
+    }
+
+    /// Like [`DuoClient::auth_status`], but returns the response envelope as
+    /// is, including failures reported by Duo, along with its
+    /// [`ResponseMeta`].
+    pub fn auth_status_raw<S: Into<String>>(
+        &self,
+        tx_id: S,
+    ) -> impl Future<Output = Result<RawResponse<AuthStatusResponse>, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
             let request =
-                Self::new_request(&this, Method::GET, "/auth/v2/check", Parameters::default())?;
-            Self::send_request_json::<CheckResponse>(&this.client, request)
-                .await
-                .map(|r| r.time)
+                Self::auth_status_request(&this, &tx_id.into(), &RequestContext::default()).await?;
+            Self::send_request_raw(&this, request).await
+        }
+    }
+
+    /// Starts an auth and waits for its result. Waiting is left to a poller
+    /// shared by all concurrent `auth_wait` calls on this client, which takes
+    /// turns between their transactions instead of each call polling on its
+    /// own.
+    pub fn auth_wait(&self, data: AuthRequest) -> impl Future<Output = Result<bool, StdError>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let status = match Self::request_auth(this.clone(), data, &RequestContext::default())
+                .await?
+            {
+                AuthStart::Pending(txid) => AuthTransaction::new(this, txid).wait_shared().await?,
+                AuthStart::Immediate(status) => status,
+            };
+            Ok(status.ready().unwrap_or_default())
         }
     }
 
+    /// Lists the transactions whose handles are still alive. Always empty
+    /// unless tracking was enabled with
+    /// [`DuoClientBuilder::track_transactions`].
+    pub fn in_flight(&self) -> Vec<TransactionSnapshot> {
+        self.0
+            .registry
+            .as_ref()
+            .map(TransactionRegistry::snapshot)
+            .unwrap_or_default()
+    }
+
+    pub fn check(&self) -> impl Future<Output = Result<u64, Error>> {
+        self.check_with_context(RequestContext::default())
+    }
+
+    /// Like [`DuoClient::check`], with the correlation header and unsigned
+    /// query parameters of `context`.
+    pub fn check_with_context(
+        &self,
+        context: RequestContext,
+    ) -> impl Future<Output = Result<u64, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move { Self::request_time(&this, "/auth/v2/check", &context).await }
+    }
+
     pub fn enroll<U: Into<String>>(
         &self,
         username: Option<U>,
@@ -145,7 +605,37 @@ impl DuoClient {
     ) -> impl Future<Output = Result<EnrollResponse, Error>> {
         let this = Arc::clone(&self.0);
 
-        async move { Self::request_enroll(this, username, valid_secs).await }
+        async move { Self::request_enroll(this, username.map(User::username), valid_secs).await }
+    }
+
+    /// Like [`DuoClient::enroll`], but for a given user, which is sent as
+    /// `user_id` or `username`. Duo only documents `username` for
+    /// enrollment, so a `user_id` depends on the backend accepting it.
+    pub fn enroll_user(
+        &self,
+        user: User,
+        valid_secs: Option<u64>,
+    ) -> impl Future<Output = Result<EnrollResponse, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move { Self::request_enroll(this, Some(user), valid_secs).await }
+    }
+
+    /// Like [`DuoClient::enroll`], but returns a session that can wait for
+    /// the enrollment to complete.
+    pub fn begin_enroll<U: Into<String>>(
+        &self,
+        username: Option<U>,
+        valid_secs: Option<u64>,
+    ) -> impl Future<Output = Result<EnrollSession, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let response =
+                Self::request_enroll(this.clone(), username.map(User::username), valid_secs)
+                    .await?;
+            Ok(EnrollSession::new(this, response))
+        }
     }
 
     pub fn enroll_status<U: Into<String>, A: Into<String>>(
@@ -158,73 +648,416 @@ impl DuoClient {
         async move { Self::request_enroll_status(this, user_id, activation_code).await }
     }
 
+    /// Polls many `(user_id, activation_code)` enrollments, at most
+    /// `concurrency` at a time, until each of them is settled. Every
+    /// enrollment is yielded once, when it reaches `success` or `invalid` or
+    /// fails with an error other than rate limiting.
+    pub fn enroll_status_many(
+        &self,
+        enrollments: Vec<(String, String)>,
+        concurrency: usize,
+        poll_interval: Duration,
+    ) -> impl Stream<Item = (String, Result<EnrollStatusResponse, Error>)> {
+        let this = Arc::clone(&self.0);
+        let state = (enrollments, VecDeque::new(), true);
+
+        stream::unfold(state, move |(mut pending, mut settled, mut first)| {
+            let this = this.clone();
+
+            async move {
+                loop {
+                    if let Some(item) = settled.pop_front() {
+                        return Some((item, (pending, settled, first)));
+                    }
+                    if pending.is_empty() {
+                        return None;
+                    }
+
+                    if !first {
+                        tokio::time::sleep(poll_interval).await;
+                    }
+                    first = false;
+
+                    let results = stream::iter(std::mem::take(&mut pending))
+                        .map(|(user_id, activation_code)| {
+                            let this = this.clone();
+                            async move {
+                                let result = Self::request_enroll_status(
+                                    this,
+                                    user_id.clone(),
+                                    activation_code.clone(),
+                                )
+                                .await;
+                                (user_id, activation_code, result)
+                            }
+                        })
+                        .buffer_unordered(concurrency.max(1))
+                        .collect::<Vec<_>>()
+                        .await;
+
+                    for (user_id, activation_code, result) in results {
+                        match result {
+                            Ok(EnrollStatusResponse::Waiting) => {
+                                pending.push((user_id, activation_code))
+                            }
+                            Err(err) if err.is_rate_limited() => {
+                                pending.push((user_id, activation_code))
+                            }
+                            result => settled.push_back((user_id, result)),
+                        }
+                    }
+                }
+            }
+        })
+    }
+
     pub fn ping(&self) -> impl Future<Output = Result<u64, Error>> {
+        self.ping_with_context(RequestContext::default())
+    }
+
+    /// Like [`DuoClient::ping`], with the correlation header and unsigned
+    /// query parameters of `context`.
+    pub fn ping_with_context(
+        &self,
+        context: RequestContext,
+    ) -> impl Future<Output = Result<u64, Error>> {
         let this = Arc::clone(&self.0);
 
+        async move { Self::request_time(&this, "/auth/v2/ping", &context).await }
+    }
+
+    /// Requests `ping` or `check`, which both answer with Duo's time.
+    async fn request_time(
+        this: &Arc<DuoClientInner>,
+        path: &'static str,
+        context: &RequestContext,
+    ) -> Result<u64, Error> {
+        #[derive(Deserialize, Debug)]
+        struct TimeResponse {
+            time: u64,
+        }
+
+        let mut request = Self::new_request(
+            this,
+            Method::GET,
+            path,
+            Parameters::default(),
+            this.timeouts.fast,
+        )
+        .await?;
+        context.apply(&mut request)?;
+        Self::send_request_json::<TimeResponse>(this, request)
+            .await
+            .map(|r| r.time)
+    }
+
+    /// Tells connectivity problems from credential problems: `ping` needs no
+    /// credentials, so Duo is unreachable if it fails, and `check` is only
+    /// tried once `ping` succeeded. A rejected integration key or signature
+    /// (codes 40102 and 40103) makes the credentials invalid, while other
+    /// failures of `check` are returned as errors.
+    pub fn health(&self) -> impl Future<Output = Result<HealthStatus, Error>> {
+        let ping = self.ping();
+        let check = self.check();
+
         async move {
-            #[derive(Deserialize, Debug)]
-            struct PingResponse {
-                time: u64,
+            match ping.await {
+                Ok(_) => {}
+                Err(err) if err.is_unavailable() => {
+                    return Ok(HealthStatus {
+                        reachable: false,
+                        credentials_valid: None,
+                    })
+                }
+                Err(err) => return Err(err),
             }
 
-            let request =
-                Self::new_request(&this, Method::GET, "/auth/v2/ping", Parameters::default())?;
-            Self::send_request_json::<PingResponse>(&this.client, request)
-                .await
-                .map(|r| r.time)
+            let credentials_valid = match check.await {
+                Ok(_) => true,
+                Err(Error::InvalidCredentials { .. }) => false,
+                Err(err) => return Err(err),
+            };
+
+            Ok(HealthStatus {
+                reachable: true,
+                credentials_valid: Some(credentials_valid),
+            })
+        }
+    }
+
+    /// Measures the local clock against Duo's through `ping`, and signs
+    /// subsequent requests with the corrected time. Returns the offset of
+    /// Duo's clock from the local one.
+    pub fn sync_time(&self) -> impl Future<Output = Result<chrono::Duration, Error>> {
+        let ping = self.ping();
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let time = ping.await?;
+            let server_time = Utc
+                .timestamp_opt(time as i64, 0)
+                .single()
+                .ok_or_else(|| Error::unspecified("invalid server time"))?;
+            Ok(this.set_server_time(server_time))
         }
     }
 
+    /// The offset currently applied to the local clock when signing.
+    pub fn clock_offset(&self) -> chrono::Duration {
+        chrono::Duration::milliseconds(self.0.clock_offset.load(Ordering::Relaxed))
+    }
+
+    /// Signs requests built from now on with `credentials`. Requests that
+    /// were already signed complete with the previous keys. With a
+    /// credential provider, the provider is asked again once its ttl elapses.
+    pub fn rotate_credentials(&self, credentials: Credentials) -> impl Future<Output = ()> {
+        let this = Arc::clone(&self.0);
+
+        async move { this.credentials.rotate(credentials).await }
+    }
+
     pub fn preauth(
         &self,
         data: PreauthRequest,
     ) -> impl Future<Output = Result<PreauthResponse, Error>> {
         let this = Arc::clone(&self.0);
 
-        async move { Self::request_preauth(this, data).await }
+        async move { Self::request_preauth(this, data, &RequestContext::default()).await }
     }
 
-    async fn request_auth(this: Arc<DuoClientInner>, data: AuthRequest) -> Result<String, Error> {
+    /// The parameters this client sends `data` with, with secrets redacted.
+    /// Unlike [`PreauthRequest::parameters`], this applies the username
+    /// normalizer set on the builder.
+    pub fn preauth_parameters(
+        &self,
+        data: &PreauthRequest,
+    ) -> Result<BTreeMap<String, String>, Error> {
         let mut parameters = Parameters::default();
-        parameters.set("async", "1");
-        data.apply(&mut parameters);
+        data.clone().apply(&mut parameters, &self.0.apply_options)?;
+        Ok(parameters.redacted_with(self.0.sensitive_parameters))
+    }
 
-        #[derive(Deserialize, Debug)]
-        struct AuthResponse {
-            txid: String,
+    /// Like [`DuoClient::preauth`], but returns the response envelope as is,
+    /// including failures reported by Duo, along with its [`ResponseMeta`].
+    pub fn preauth_raw(
+        &self,
+        data: PreauthRequest,
+    ) -> impl Future<Output = Result<RawResponse<PreauthResponse>, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let request = Self::preauth_request(&this, data, &RequestContext::default()).await?;
+            Self::send_request_raw(&this, request).await
         }
+    }
 
-        let request = Self::new_request(&this, Method::POST, "/auth/v2/auth", parameters)?;
-        Self::send_request_json::<AuthResponse>(&this.client, request)
-            .await
-            .map(|r| r.txid)
+    pub fn preauth_with_context(
+        &self,
+        data: PreauthRequest,
+        context: RequestContext,
+    ) -> impl Future<Output = Result<PreauthResponse, Error>> {
+        let this = Arc::clone(&self.0);
+
+        instrument!(
+            async move { Self::request_preauth(this, data, &context).await },
+            "duo_preauth",
+            correlation_id = context.correlation_id()
+        )
+    }
+
+    /// Runs preauth, and when the user needs to enroll and `auto_enroll` is
+    /// set, also starts an enrollment for them, so the activation code can be
+    /// shown right away next to the enrollment portal.
+    pub fn preauth_or_enroll(
+        &self,
+        user: User,
+        auto_enroll: bool,
+    ) -> impl Future<Output = Result<PreauthOrEnroll, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let preauth = PreauthRequest::new(user.clone());
+            let enroll_portal_url =
+                match Self::request_preauth(this.clone(), preauth, &RequestContext::default())
+                    .await?
+                {
+                    PreauthResponse::Enroll { enroll_portal_url } => enroll_portal_url,
+                    response => return Ok(PreauthOrEnroll::Preauth(response)),
+                };
+
+            let activation = if auto_enroll {
+                Some(Self::request_enroll(this, Some(user), None).await?)
+            } else {
+                None
+            };
+
+            Ok(PreauthOrEnroll::NeedsEnrollment {
+                enroll_portal_url,
+                activation,
+            })
+        }
     }
 
-    async fn request_auth_status(
+    /// Runs preauth, and under [`Failmode::Open`] treats Duo being
+    /// unavailable, see [`Error::is_unavailable`], as an `allow`.
+    pub fn preauth_with_failmode(
+        &self,
+        data: PreauthRequest,
+        failmode: Failmode,
+    ) -> impl Future<Output = Result<PreauthOutcome, Error>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            match Self::request_preauth(this, data, &RequestContext::default()).await {
+                Ok(response) => Ok(PreauthOutcome {
+                    response,
+                    failmode_allow: false,
+                }),
+                Err(err) if failmode == Failmode::Open && err.is_unavailable() => {
+                    Ok(PreauthOutcome {
+                        response: PreauthResponse::Allow {
+                            status_msg: None,
+                            trusted_device_token: None,
+                            trusted_device_token_expiration: None,
+                        },
+                        failmode_allow: true,
+                    })
+                }
+                Err(err) => Err(err),
+            }
+        }
+    }
+
+    pub fn preauth_batch(
+        &self,
+        requests: Vec<PreauthRequest>,
+        concurrency: usize,
+    ) -> impl Future<Output = BatchResult<PreauthResponse>> {
+        let this = Arc::clone(&self.0);
+
+        async move {
+            let results =
+                stream::iter(requests)
+                    .map(|data| {
+                        let this = this.clone();
+                        async move {
+                            Self::request_preauth(this, data, &RequestContext::default()).await
+                        }
+                    })
+                    .buffered(concurrency.max(1))
+                    .collect::<Vec<_>>()
+                    .await;
+
+            BatchResult::new(results)
+        }
+    }
+
+    async fn request_auth(
+        this: Arc<DuoClientInner>,
+        data: AuthRequest,
+        context: &RequestContext,
+    ) -> Result<AuthStart, Error> {
+        let fut = async move {
+            data.validate()?;
+
+            let mut parameters = Parameters::default();
+            let timeout = if this.force_async {
+                parameters.set("async", "1");
+                this.timeouts.fast
+            } else {
+                this.timeouts.slow
+            };
+            data.apply(&mut parameters, &this.apply_options)?;
+
+            let mut request =
+                Self::new_request(&this, Method::POST, "/auth/v2/auth", parameters, timeout)
+                    .await?;
+            context.apply(&mut request)?;
+            let start = Self::send_request_json::<AuthStart>(&this, request).await?;
+
+            // Duo's txids are UUIDs, but compatible backends may use other
+            // forms, so only reject what cannot be sent back in `auth_status`
+            if let Some(txid) = start.txid() {
+                if txid.is_empty() || !txid.chars().all(|c| c.is_ascii_graphic()) {
+                    return Err(Error::MalformedResponse {
+                        reason: format!("invalid txid {:?}", txid),
+                    });
+                }
+                // Not a secret, status can only be queried with the skey
+                #[cfg(feature = "tracing")]
+                tracing::Span::current().record("txid", txid);
+            }
+            Ok(start)
+        };
+
+        instrument!(
+            fut,
+            "duo_auth",
+            correlation_id = context.correlation_id(),
+            txid = tracing::field::Empty
+        )
+        .await
+    }
+
+    pub(crate) async fn request_auth_status(
         this: Arc<DuoClientInner>,
         tx_id: &str,
+        context: &RequestContext,
     ) -> Result<AuthStatusResponse, Error> {
+        let fut = async {
+            let request = Self::auth_status_request(&this, tx_id, context).await?;
+            Self::send_request_json(&this, request).await
+        };
+
+        instrument!(fut, "duo_auth_status", txid = tx_id).await
+    }
+
+    async fn auth_status_request(
+        this: &Arc<DuoClientInner>,
+        tx_id: &str,
+        context: &RequestContext,
+    ) -> Result<Request, Error> {
         let mut parameters = Parameters::default();
         parameters.set("txid", tx_id);
 
-        let request = Self::new_request(&this, Method::GET, "/auth/v2/auth_status", parameters)?;
-        Self::send_request_json(&this.client, request).await
+        let mut request = Self::new_request(
+            this,
+            Method::GET,
+            "/auth/v2/auth_status",
+            parameters,
+            this.timeouts.slow,
+        )
+        .await?;
+        context.apply(&mut request)?;
+        Ok(request)
     }
 
-    async fn request_enroll<U: Into<String>>(
+    async fn request_enroll(
         this: Arc<DuoClientInner>,
-        username: Option<U>,
+        user: Option<User>,
         valid_secs: Option<u64>,
     ) -> Result<EnrollResponse, Error> {
         let mut parameters = Parameters::default();
-        parameters.set_opt("username", username);
+        if let Some(user) = user {
+            user.validate()?;
+            user.apply(&mut parameters, &this.apply_options);
+        }
         parameters.set_opt("valid_secs", valid_secs.map(|v| v.to_string()));
 
-        let request = Self::new_request(&this, Method::POST, "/auth/v2/enroll", parameters)?;
-        Self::send_request_json(&this.client, request).await
+        let request = Self::new_request(
+            &this,
+            Method::POST,
+            "/auth/v2/enroll",
+            parameters,
+            this.timeouts.fast,
+        )
+        .await?;
+        Self::send_request_json(&this, request)
+            .await
+            .map_err(Error::into_already_enrolled)
     }
 
-    async fn request_enroll_status<U: Into<String>, A: Into<String>>(
+    pub(crate) async fn request_enroll_status<U: Into<String>, A: Into<String>>(
         this: Arc<DuoClientInner>,
         user_id: U,
         activation_code: A,
@@ -233,43 +1066,206 @@ impl DuoClient {
         parameters.set("user_id", user_id);
         parameters.set("activation_code", activation_code);
 
-        let request = Self::new_request(&this, Method::POST, "/auth/v2/enroll_status", parameters)?;
-        Self::send_request_json(&this.client, request).await
+        let request = Self::new_request(
+            &this,
+            Method::POST,
+            "/auth/v2/enroll_status",
+            parameters,
+            this.timeouts.fast,
+        )
+        .await?;
+        Self::send_request_json(&this, request).await
     }
 
     async fn request_preauth(
         this: Arc<DuoClientInner>,
         data: PreauthRequest,
+        context: &RequestContext,
     ) -> Result<PreauthResponse, Error> {
+        let request = Self::preauth_request(&this, data, context).await?;
+        Self::send_request_json(&this, request).await
+    }
+
+    async fn preauth_request(
+        this: &Arc<DuoClientInner>,
+        data: PreauthRequest,
+        context: &RequestContext,
+    ) -> Result<Request, Error> {
         let mut parameters = Parameters::default();
-        data.apply(&mut parameters);
+        data.apply(&mut parameters, &this.apply_options)?;
 
-        let request = Self::new_request(&this, Method::POST, "/auth/v2/preauth", parameters)?;
-        Self::send_request_json(&this.client, request).await
+        let mut request = Self::new_request(
+            this,
+            Method::POST,
+            "/auth/v2/preauth",
+            parameters,
+            this.timeouts.fast,
+        )
+        .await?;
+        context.apply(&mut request)?;
+        Ok(request)
     }
 
-    fn new_request<P: Into<String>>(
+    pub(crate) async fn new_request<P: Into<String>>(
         this: &Arc<DuoClientInner>,
         method: Method,
         path: P,
         parameters: Parameters,
+        timeout: Duration,
     ) -> Result<Request, Error> {
-        DuoRequest::new(this.base_url.clone(), method, path, parameters)
-            .build(&this.client, &this.ikey, &this.skey)
-            .map_err(Error::unspecified)
+        let credentials = this.credentials.resolve().await?;
+
+        let timeout = match DEADLINE.try_with(|deadline| *deadline) {
+            Ok(deadline) => match deadline.checked_duration_since(Instant::now()) {
+                Some(remaining) if !remaining.is_zero() => timeout.min(remaining),
+                _ => return Err(Error::DeadlineExceeded),
+            },
+            Err(_) => timeout,
+        };
+
+        let mut request = DuoRequest::new(this.base_url.clone(), method, path, parameters)
+            .with_date(this.now())
+            .with_content_type(&this.content_type)
+            .with_user_agent(this.user_agent.clone());
+        for (name, value) in &this.signed_headers {
+            request = request.with_signed_header(name, value());
+        }
+        if let Some(host) = &this.signing_host {
+            request = request.with_signing_host(host);
+        }
+        let mut request = request
+            .build(&this.client, &credentials.ikey, &credentials.skey)
+            .map_err(Error::from_std)?;
+        *request.timeout_mut() = Some(timeout);
+        Ok(request)
     }
 
-    async fn send_request_json<T>(client: &Client, request: Request) -> Result<T, Error>
+    pub(crate) async fn send_request_json<T>(
+        this: &DuoClientInner,
+        request: Request,
+    ) -> Result<T, Error>
     where
         T: DeserializeOwned + std::fmt::Debug,
     {
-        let response = client.execute(request).await.map_err(Error::unspecified)?;
+        #[cfg(feature = "otel")]
+        let span = otel::request_span(&request);
 
-        let body = response
-            .json::<DuoResponse<T>>()
-            .await
-            .map_err(Error::unspecified)?;
+        #[cfg(feature = "capture-bodies")]
+        let captured_request = this.capture_bodies.then(|| {
+            request::redact_serialized(&request_parameters(&request), this.sensitive_parameters)
+        });
+
+        let fut = async move {
+            let _permit = this.acquire_slot().await?;
+            let response = Self::send_request(this, request).await?;
+
+            #[cfg(feature = "capture-bodies")]
+            if let Some(request) = captured_request {
+                let bytes = response::read_body(response, this.max_response_bytes).await?;
+                let body =
+                    serde_json::from_slice::<DuoResponse<T>>(&bytes).map_err(Error::unspecified)?;
+                #[cfg(feature = "otel")]
+                otel::record_response(&body);
+
+                return body.ok().map_err(|err| {
+                    err.with_captured(CapturedBodies {
+                        request,
+                        response: String::from_utf8_lossy(&bytes).into_owned(),
+                    })
+                });
+            }
+
+            let bytes = response::read_body(response, this.max_response_bytes).await?;
+            let body =
+                serde_json::from_slice::<DuoResponse<T>>(&bytes).map_err(Error::unspecified)?;
+            #[cfg(feature = "otel")]
+            otel::record_response(&body);
+
+            body.ok()
+        };
+
+        #[cfg(feature = "otel")]
+        let fut = tracing::Instrument::instrument(fut, span);
+        fut.await
+    }
+
+    pub(crate) async fn send_request_raw<T>(
+        this: &DuoClientInner,
+        request: Request,
+    ) -> Result<RawResponse<T>, Error>
+    where
+        T: DeserializeOwned + std::fmt::Debug,
+    {
+        #[cfg(feature = "otel")]
+        let span = otel::request_span(&request);
+
+        let fut = async move {
+            let _permit = this.acquire_slot().await?;
+            let started = Instant::now();
+            let response = Self::send_request(this, request).await?;
+            let headers = response.headers().clone();
+            let bytes = response::read_body(response, this.max_response_bytes).await?;
+            let body =
+                serde_json::from_slice::<DuoResponse<T>>(&bytes).map_err(Error::unspecified)?;
+            #[cfg(feature = "otel")]
+            otel::record_response(&body);
+
+            Ok(RawResponse {
+                body,
+                meta: ResponseMeta::new(started.elapsed(), &headers, &bytes),
+            })
+        };
+
+        #[cfg(feature = "otel")]
+        let fut = tracing::Instrument::instrument(fut, span);
+        fut.await
+    }
+
+    async fn send_request(this: &DuoClientInner, request: Request) -> Result<Response, Error> {
+        if let Some(breaker) = &this.circuit_breaker {
+            breaker.acquire()?;
+        }
+
+        let response = match this.execute(request).await {
+            Ok(response) if response.status().is_server_error() => Err(Error::ServiceUnavailable {
+                status: response.status().as_u16(),
+                retry_after: response::retry_after(response.headers(), this.clock.now()),
+            }),
+            Ok(response) => Ok(response),
+            Err(err) => Err(Error::unspecified(err)),
+        };
+
+        if let Some(breaker) = &this.circuit_breaker {
+            breaker.record(response.is_ok());
+        }
+
+        let response = response?;
+
+        if this.auto_sync_time {
+            if let Some(date) = response
+                .headers()
+                .get(DATE)
+                .and_then(|date| date.to_str().ok())
+                .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+            {
+                this.set_server_time(date.with_timezone(&Utc));
+            }
+        }
+
+        if response.status() == StatusCode::TOO_MANY_REQUESTS {
+            return Err(Error::RateLimited {
+                retry_after: response::retry_after(response.headers(), this.clock.now()),
+            });
+        }
+
+        Ok(response)
+    }
+}
 
-        body.ok()
+#[cfg(feature = "capture-bodies")]
+fn request_parameters(request: &Request) -> String {
+    match request.body().and_then(|body| body.as_bytes()) {
+        Some(body) => String::from_utf8_lossy(body).into_owned(),
+        None => request.url().query().unwrap_or_default().to_string(),
     }
 }
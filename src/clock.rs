@@ -0,0 +1,17 @@
+use chrono::{DateTime, Utc};
+
+/// The source of wall-clock time used for signing requests and judging
+/// expiry, replaceable in tests. Waiting between polls is unaffected and
+/// follows tokio's timer.
+pub trait Clock: Send + Sync {
+    fn now(&self) -> DateTime<Utc>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> DateTime<Utc> {
+        Utc::now()
+    }
+}
@@ -0,0 +1,108 @@
+use std::{
+    future::Future,
+    pin::Pin,
+    sync::{Arc, PoisonError, RwLock},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::Mutex;
+
+use super::{errors::Error, StdError};
+
+#[derive(Clone)]
+pub struct Credentials {
+    pub ikey: String,
+    pub skey: String,
+}
+
+impl Credentials {
+    pub fn new<I: Into<String>, S: Into<String>>(ikey: I, skey: S) -> Self {
+        Self {
+            ikey: ikey.into(),
+            skey: skey.into(),
+        }
+    }
+}
+
+pub type CredentialsFuture<'a> =
+    Pin<Box<dyn Future<Output = Result<Credentials, StdError>> + Send + 'a>>;
+
+/// Supplies the integration and secret keys, e.g. from a secret store.
+pub trait CredentialProvider: Send + Sync {
+    fn credentials(&self) -> CredentialsFuture<'_>;
+}
+
+/// Every request is signed with the credentials resolved while building it,
+/// which are held as a whole behind an [`Arc`]. Rotating them swaps the
+/// `Arc`, so a request never sees the ikey of one pair and the skey of
+/// another, and requests already built keep their old pair.
+pub(crate) enum CredentialSource {
+    Static(RwLock<Arc<Credentials>>),
+    Provider {
+        provider: Box<dyn CredentialProvider>,
+        ttl: Duration,
+        cached: Mutex<Option<(Arc<Credentials>, Instant)>>,
+    },
+}
+
+impl CredentialSource {
+    pub(crate) fn fixed(credentials: Credentials) -> Self {
+        Self::Static(RwLock::new(Arc::new(credentials)))
+    }
+
+    pub(crate) fn provider<P: CredentialProvider + 'static>(provider: P, ttl: Duration) -> Self {
+        Self::Provider {
+            provider: Box::new(provider),
+            ttl,
+            cached: Mutex::new(None),
+        }
+    }
+
+    /// Returns the credentials to sign the next request with. Provided
+    /// credentials are reused until `ttl` elapses, and concurrent callers wait
+    /// for a single refresh rather than hitting the provider each.
+    pub(crate) async fn resolve(&self) -> Result<Arc<Credentials>, Error> {
+        match self {
+            Self::Static(credentials) => Ok(Arc::clone(
+                &credentials.read().unwrap_or_else(PoisonError::into_inner),
+            )),
+            Self::Provider {
+                provider,
+                ttl,
+                cached,
+            } => {
+                let mut cached = cached.lock().await;
+                if let Some((credentials, fetched)) = cached.as_ref() {
+                    if fetched.elapsed() < *ttl {
+                        return Ok(Arc::clone(credentials));
+                    }
+                }
+
+                let credentials = Arc::new(
+                    provider
+                        .credentials()
+                        .await
+                        .map_err(Error::CredentialsUnavailable)?,
+                );
+                *cached = Some((Arc::clone(&credentials), Instant::now()));
+
+                Ok(credentials)
+            }
+        }
+    }
+
+    /// Replaces the credentials used for requests built from now on. Provided
+    /// credentials count as freshly obtained, and are replaced by the
+    /// provider's once `ttl` elapses.
+    pub(crate) async fn rotate(&self, credentials: Credentials) {
+        let credentials = Arc::new(credentials);
+        match self {
+            Self::Static(current) => {
+                *current.write().unwrap_or_else(PoisonError::into_inner) = credentials;
+            }
+            Self::Provider { cached, .. } => {
+                *cached.lock().await = Some((credentials, Instant::now()));
+            }
+        }
+    }
+}
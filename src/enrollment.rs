@@ -0,0 +1,102 @@
+use std::{sync::Arc, time::Duration};
+
+use chrono::{DateTime, Utc};
+use tokio::time::Instant;
+
+use super::{
+    client::{DuoClient, DuoClientInner},
+    errors::Error,
+    types::{EnrollResponse, EnrollStatusResponse},
+};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum EnrollOutcome {
+    Activated,
+    /// Duo no longer recognizes the activation code, though it has not
+    /// expired yet
+    Invalid,
+    /// The wait timed out while the enrollment was still pending
+    TimedOut,
+}
+
+/// An enrollment started with [`DuoClient::begin_enroll`], keeping the
+/// `user_id` and `activation_code` needed to check on it.
+pub struct EnrollSession {
+    client: Arc<DuoClientInner>,
+    response: EnrollResponse,
+}
+
+impl EnrollSession {
+    pub(crate) fn new(client: Arc<DuoClientInner>, response: EnrollResponse) -> Self {
+        Self { client, response }
+    }
+
+    /// The full response, including the username Duo enrolled the user as.
+    pub fn response(&self) -> &EnrollResponse {
+        &self.response
+    }
+
+    pub fn user_id(&self) -> &str {
+        &self.response.user_id
+    }
+
+    pub fn activation_code(&self) -> &str {
+        &self.response.activation_code
+    }
+
+    /// URL of a QR code image to scan with Duo Mobile.
+    pub fn activation_barcode(&self) -> &str {
+        &self.response.activation_barcode
+    }
+
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        self.response.expires_at()
+    }
+
+    /// Whether the activation code has expired, judged by the client's
+    /// clock including any offset from time synchronization.
+    pub fn is_expired(&self) -> bool {
+        self.expires_at()
+            .map_or(false, |expires_at| self.client.now() >= expires_at)
+    }
+
+    pub async fn status(&self) -> Result<EnrollStatusResponse, Error> {
+        DuoClient::request_enroll_status(
+            Arc::clone(&self.client),
+            self.user_id(),
+            self.activation_code(),
+        )
+        .await
+    }
+
+    /// Polls `enroll_status` every `poll_interval` until the enrollment is
+    /// settled or `timeout` passes. Fails with [`Error::EnrollmentExpired`]
+    /// once the activation code has expired.
+    pub async fn wait(
+        &self,
+        poll_interval: Duration,
+        timeout: Duration,
+    ) -> Result<EnrollOutcome, Error> {
+        let deadline = Instant::now() + timeout;
+
+        loop {
+            if self.is_expired() {
+                return Err(Error::EnrollmentExpired);
+            }
+
+            match self.status().await? {
+                EnrollStatusResponse::Success => return Ok(EnrollOutcome::Activated),
+                EnrollStatusResponse::Invalid if self.is_expired() => {
+                    return Err(Error::EnrollmentExpired)
+                }
+                EnrollStatusResponse::Invalid => return Ok(EnrollOutcome::Invalid),
+                EnrollStatusResponse::Waiting => {}
+            }
+
+            if Instant::now() + poll_interval >= deadline {
+                return Ok(EnrollOutcome::TimedOut);
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    }
+}
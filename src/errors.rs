@@ -1,6 +1,11 @@
+use std::{sync::Arc, time::Duration};
+
 use thiserror::Error;
 
-use super::StdError;
+use super::{
+    types::{AuthStatusResponse, DenyReason, DeviceCapability},
+    StdError,
+};
 
 #[derive(Error, Debug)]
 pub enum Error {
@@ -12,14 +17,299 @@ pub enum Error {
         code: u64,
         message: String,
         message_detail: Option<String>,
+        /// The exchange that failed, only ever set with the `capture-bodies`
+        /// feature
+        captured: Option<Box<CapturedBodies>>,
+    },
+
+    /// Duo answered successfully, but with a response that cannot be used
+    #[error("Malformed response: {reason}")]
+    MalformedResponse { reason: String },
+
+    /// The response envelope has a `stat` other than `OK` or `FAIL`
+    #[error("Unexpected response stat {stat:?}")]
+    UnexpectedStat { stat: String },
+
+    /// Duo rejected the integration key (code 40102) or the signature made
+    /// with the secret key (code 40103). `code` is Duo's error code.
+    #[error("Invalid credentials: {message} ({code})")]
+    InvalidCredentials { code: u64, message: String },
+
+    #[error("Rate limited by Duo")]
+    RateLimited { retry_after: Option<Duration> },
+
+    /// Duo answered with a 5xx, e.g. during a maintenance window
+    #[error("Duo service unavailable (HTTP {status})")]
+    ServiceUnavailable {
+        status: u16,
+        retry_after: Option<Duration>,
     },
 
+    /// Requests are short-circuited after repeated failures, see
+    /// [`DuoClientBuilder::circuit_breaker`](crate::DuoClientBuilder::circuit_breaker)
+    #[error("Circuit breaker open, retry in {retry_after:?}")]
+    CircuitOpen { retry_after: Duration },
+
+    /// The client has as many requests in flight as its concurrency limit
+    /// allows, see [`WhenLimited::Fail`](crate::request::WhenLimited::Fail)
+    #[error("Too many concurrent requests, limit is {limit}")]
+    ConcurrencyLimited { limit: usize },
+
+    #[error("Invalid parameter '{name}': {reason}")]
+    InvalidParameter { name: &'static str, reason: String },
+
+    #[error("Device '{device}' does not support {capability:?}")]
+    UnsupportedFactor {
+        device: String,
+        capability: DeviceCapability,
+    },
+
+    /// The serialized parameters exceed [`MAX_QUERY_LENGTH`](crate::request::MAX_QUERY_LENGTH)
+    /// or [`MAX_BODY_LENGTH`](crate::request::MAX_BODY_LENGTH)
+    #[error("Request parameters too large: {size} bytes, limit is {limit}")]
+    ParametersTooLarge { size: usize, limit: usize },
+
+    /// The response body exceeds the limit set with
+    /// [`DuoClientBuilder::max_response_bytes`](crate::DuoClientBuilder::max_response_bytes)
+    #[error("Response too large, limit is {limit} bytes")]
+    ResponseTooLarge { limit: usize },
+
+    /// The deadline given to [`DuoClient::with_deadline`](crate::DuoClient::with_deadline) passed
+    #[error("Deadline exceeded")]
+    DeadlineExceeded,
+
+    /// Enrollment was refused because the user already exists. Duo reports
+    /// this as code 40002 with `username` as the invalid parameter, and does
+    /// not say which user it is, so `user_id` is only set when Duo includes
+    /// one in the detail.
+    #[error("User is already enrolled")]
+    AlreadyEnrolled { user_id: Option<String> },
+
+    #[error("Enrollment activation code expired")]
+    EnrollmentExpired,
+
+    /// The auth ended without approval. `reason` is `None` if it failed
+    /// rather than being denied, e.g. because the push could not be
+    /// delivered.
+    #[error("Auth denied: {status_msg}")]
+    AuthDenied {
+        reason: Option<DenyReason>,
+        status_msg: String,
+    },
+
+    /// The auth is still waiting for the user to answer
+    #[error("Auth still waiting for the user")]
+    StillWaiting,
+
+    /// Duo refused to resynchronize the token with the given codes, see
+    /// `DuoClient::resync_token`, which needs the `admin` feature
+    #[error("Token '{token_id}' is out of sync")]
+    TokenOutOfSync { token_id: String },
+
+    /// Duo decided the auth right away instead of returning a txid, which
+    /// methods handing out txids cannot represent. The result is kept here;
+    /// [`DuoClient::auth_start`](crate::DuoClient::auth_start) handles both.
+    #[error("Auth completed without a txid")]
+    AuthCompleted(Box<AuthStatusResponse>),
+
+    #[error("Failed to obtain credentials: {0}")]
+    CredentialsUnavailable(StdError),
+
+    /// An error shared between callers of the same idempotent auth
+    #[error(transparent)]
+    Shared(Arc<Error>),
+
     #[error("Unspecified error")]
     Unspecified(#[from] StdError),
 }
 
+#[derive(Debug)]
+pub struct CapturedBodies {
+    /// Form-encoded request parameters, with secrets redacted
+    pub request: String,
+    pub response: String,
+}
+
 impl Error {
     pub(crate) fn unspecified<E: Into<StdError>>(err: E) -> Self {
         Self::Unspecified(err.into())
     }
+
+    /// Recovers an [`Error`] that was boxed into a [`StdError`].
+    pub(crate) fn from_std(err: StdError) -> Self {
+        match err.downcast::<Self>() {
+            Ok(err) => *err,
+            Err(err) => Self::Unspecified(err),
+        }
+    }
+
+    #[cfg(feature = "capture-bodies")]
+    pub(crate) fn with_captured(mut self, bodies: CapturedBodies) -> Self {
+        if let Self::ApiRequestFailed { captured, .. } = &mut self {
+            *captured = Some(Box::new(bodies));
+        }
+        self
+    }
+
+    /// Turns a rejected `username` of an enroll request into
+    /// [`Error::AlreadyEnrolled`].
+    pub(crate) fn into_already_enrolled(self) -> Self {
+        let hints = self.detail_hints();
+        match &self {
+            Self::ApiRequestFailed { code: 40002, .. }
+                if hints.iter().any(|hint| hint == "username") =>
+            {
+                Self::AlreadyEnrolled {
+                    user_id: hints
+                        .into_iter()
+                        .find(|hint| hint.starts_with("DU") && hint.len() == 20),
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Turns rejected codes of a token resync into [`Error::TokenOutOfSync`].
+    /// Duo answers HTTP 400 both for malformed parameters and for codes that
+    /// do not resynchronize the token, and only tells the latter apart by
+    /// its message, "Resync failed", so other 400s are kept as they are.
+    #[cfg(feature = "admin")]
+    pub(crate) fn into_token_out_of_sync(self, token_id: &str) -> Self {
+        let refused = |text: &str| text.to_ascii_lowercase().contains("resync");
+        match &self {
+            Self::ApiRequestFailed {
+                code,
+                message,
+                message_detail,
+                ..
+            } if code / 100 == 400
+                && (refused(message) || message_detail.as_deref().map_or(false, refused)) =>
+            {
+                Self::TokenOutOfSync {
+                    token_id: token_id.to_string(),
+                }
+            }
+            _ => self,
+        }
+    }
+
+    /// Whether Duo could not be reached at all, as opposed to Duo rejecting
+    /// the request.
+    pub fn is_network(&self) -> bool {
+        match self {
+            Self::Unspecified(err) => err
+                .downcast_ref::<reqwest::Error>()
+                .map(|err| err.is_timeout() || err.is_connect() || err.is_request())
+                .unwrap_or(false),
+            Self::Shared(err) => err.is_network(),
+            _ => false,
+        }
+    }
+
+    /// Whether Duo could not serve the request: it was unreachable, answered
+    /// with a 5xx, or the circuit breaker is open after such failures.
+    pub fn is_unavailable(&self) -> bool {
+        match self {
+            Self::Shared(err) => err.is_unavailable(),
+            Self::ServiceUnavailable { .. } | Self::CircuitOpen { .. } => true,
+            _ => self.is_network(),
+        }
+    }
+
+    pub(crate) fn is_timeout(&self) -> bool {
+        match self {
+            Self::Unspecified(err) => err
+                .downcast_ref::<reqwest::Error>()
+                .map(reqwest::Error::is_timeout)
+                .unwrap_or(false),
+            Self::Shared(err) => err.is_timeout(),
+            _ => false,
+        }
+    }
+
+    pub fn is_rate_limited(&self) -> bool {
+        match self {
+            Self::Shared(err) => err.is_rate_limited(),
+            _ => matches!(
+                self,
+                Self::RateLimited { .. } | Self::ApiRequestFailed { code: 42901, .. }
+            ),
+        }
+    }
+
+    /// Whether the same request may succeed if retried later.
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            Self::Shared(err) => err.is_retryable(),
+            Self::ServiceUnavailable { .. }
+            | Self::CircuitOpen { .. }
+            | Self::ConcurrencyLimited { .. } => true,
+            _ => self.is_network() || self.is_rate_limited(),
+        }
+    }
+
+    /// Splits the `message_detail` of [`Error::ApiRequestFailed`] into its
+    /// parts, best effort: Duo separates hints with `|`, and lists parameter
+    /// names with `,`. A detail in neither form is returned as the only
+    /// element. Empty for other errors or when there is no detail.
+    pub fn detail_hints(&self) -> Vec<String> {
+        let detail = match self {
+            Self::ApiRequestFailed {
+                message_detail: Some(detail),
+                ..
+            } => detail,
+            Self::Shared(err) => return err.detail_hints(),
+            _ => return Vec::new(),
+        };
+
+        let separator = if detail.contains('|') { '|' } else { ',' };
+        detail
+            .split(separator)
+            .map(str::trim)
+            .filter(|hint| !hint.is_empty())
+            .map(String::from)
+            .collect()
+    }
+
+    /// Suggests an HTTP status for responding to a downstream caller.
+    ///
+    /// Duo error codes embed the HTTP status in their first three digits, so
+    /// client errors are passed through as such (e.g. 40002 becomes 400, 42901
+    /// becomes 429), and keys Duo rejects become 401. Duo 5xx responses
+    /// become 503, other Duo-side failures and network errors become 502,
+    /// timeouts become 504, and other misconfiguration becomes 500.
+    pub fn http_status_hint(&self) -> u16 {
+        match self {
+            Self::InvalidApiDomain { .. }
+            | Self::CredentialsUnavailable(_)
+            | Self::AuthCompleted(_) => 500,
+            Self::InvalidCredentials { .. } => 401,
+            Self::InvalidParameter { .. }
+            | Self::UnsupportedFactor { .. }
+            | Self::ParametersTooLarge { .. } => 400,
+            Self::RateLimited { .. } => 429,
+            Self::EnrollmentExpired => 410,
+            Self::AlreadyEnrolled { .. } | Self::StillWaiting => 409,
+            Self::AuthDenied { .. } => 403,
+            Self::TokenOutOfSync { .. } => 400,
+            Self::DeadlineExceeded => 504,
+            Self::ServiceUnavailable { .. }
+            | Self::CircuitOpen { .. }
+            | Self::ConcurrencyLimited { .. } => 503,
+            Self::Shared(err) => err.http_status_hint(),
+            Self::ApiRequestFailed { code, .. } => match code / 100 {
+                status @ 400..=499 => status as u16,
+                _ => 502,
+            },
+            Self::ResponseTooLarge { .. }
+            | Self::MalformedResponse { .. }
+            | Self::UnexpectedStat { .. } => 502,
+            Self::Unspecified(err) if err.is::<serde_json::Error>() => 502,
+            Self::Unspecified(err) => match err.downcast_ref::<reqwest::Error>() {
+                Some(err) if err.is_timeout() => 504,
+                Some(err) if err.is_connect() || err.is_request() || err.is_decode() => 502,
+                _ => 500,
+            },
+        }
+    }
 }
@@ -0,0 +1,50 @@
+use std::{
+    collections::HashMap,
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::{Duration, Instant},
+};
+
+use tokio::sync::watch;
+
+use super::errors::Error;
+
+/// How long the txid of an idempotent auth is remembered. Duo transactions
+/// expire well before this.
+pub(crate) const IDEMPOTENCY_TTL: Duration = Duration::from_secs(300);
+
+pub(crate) type StartOutcome = Option<Result<String, Arc<Error>>>;
+
+pub(crate) enum Slot {
+    /// An auth with the same key was already started
+    Existing(watch::Receiver<StartOutcome>),
+    /// The caller has to start the auth and report its outcome
+    New(watch::Sender<StartOutcome>, watch::Receiver<StartOutcome>),
+}
+
+#[derive(Default)]
+pub(crate) struct IdempotentAuths {
+    entries: Mutex<HashMap<String, (Instant, watch::Receiver<StartOutcome>)>>,
+}
+
+impl IdempotentAuths {
+    fn entries(&self) -> MutexGuard<'_, HashMap<String, (Instant, watch::Receiver<StartOutcome>)>> {
+        self.entries.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub(crate) fn slot(&self, key: &str) -> Slot {
+        let mut entries = self.entries();
+        entries.retain(|_, (started, _)| started.elapsed() < IDEMPOTENCY_TTL);
+
+        if let Some((_, receiver)) = entries.get(key) {
+            return Slot::Existing(receiver.clone());
+        }
+
+        let (sender, receiver) = watch::channel(None);
+        entries.insert(key.to_string(), (Instant::now(), receiver.clone()));
+        Slot::New(sender, receiver)
+    }
+
+    pub(crate) fn forget(&self, key: &str) {
+        self.entries().remove(key);
+    }
+}
@@ -1,9 +1,33 @@
+#[macro_use]
+mod macros;
+
+#[cfg(feature = "admin")]
+pub mod admin;
+pub mod batch;
+pub mod builder;
+mod circuit;
 pub mod client;
+pub mod clock;
+pub mod credentials;
+pub mod enrollment;
 pub mod errors;
+mod idempotency;
+mod limit;
+#[cfg(feature = "otel")]
+mod otel;
+mod poller;
+pub mod random;
+pub mod region;
 pub mod request;
 pub mod response;
+pub mod signing;
+pub mod transaction;
 pub mod types;
 
 pub(crate) type StdError = Box<dyn std::error::Error + Send + Sync>;
 
+pub use builder::DuoClientBuilder;
 pub use client::DuoClient;
+pub use enrollment::EnrollSession;
+pub use region::DuoRegion;
+pub use transaction::AuthTransaction;
@@ -0,0 +1,34 @@
+use tokio::sync::{Semaphore, SemaphorePermit};
+
+use super::{errors::Error, request::WhenLimited};
+
+pub(crate) struct ConcurrencyLimiter {
+    limit: usize,
+    when_limited: WhenLimited,
+    semaphore: Semaphore,
+}
+
+impl ConcurrencyLimiter {
+    pub(crate) fn new(limit: usize, when_limited: WhenLimited) -> Self {
+        let limit = limit.max(1);
+
+        Self {
+            limit,
+            when_limited,
+            semaphore: Semaphore::new(limit),
+        }
+    }
+
+    /// Waits for a free slot, or fails with [`Error::ConcurrencyLimited`]
+    /// right away when configured to. The slot is released when the permit
+    /// is dropped.
+    pub(crate) async fn acquire(&self) -> Result<SemaphorePermit<'_>, Error> {
+        let permit = match self.when_limited {
+            // The semaphore is never closed
+            WhenLimited::Wait => self.semaphore.acquire().await.ok(),
+            WhenLimited::Fail => self.semaphore.try_acquire().ok(),
+        };
+
+        permit.ok_or(Error::ConcurrencyLimited { limit: self.limit })
+    }
+}
@@ -0,0 +1,16 @@
+/// Wraps a future in a tracing span when the `tracing` feature is enabled, and
+/// evaluates to the bare future otherwise. The span is created before the
+/// future, so its fields may borrow values that the future moves.
+macro_rules! instrument {
+    ($fut:expr, $($span:tt)+) => {{
+        #[cfg(feature = "tracing")]
+        {
+            let span = tracing::info_span!($($span)+);
+            tracing::Instrument::instrument($fut, span)
+        }
+        #[cfg(not(feature = "tracing"))]
+        {
+            $fut
+        }
+    }};
+}
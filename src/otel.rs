@@ -0,0 +1,40 @@
+//! Span attributes following the OpenTelemetry semantic conventions, picked
+//! up by `tracing-opentelemetry` when it is installed as a subscriber layer.
+
+use reqwest::Request;
+use tracing::{field::Empty, Span};
+
+use super::response::DuoResponse;
+
+/// A client span for a single Duo API call. Only the scheme and host of the
+/// URL are recorded, as the query string carries the request parameters.
+pub(crate) fn request_span(request: &Request) -> Span {
+    let url = request.url();
+
+    tracing::info_span!(
+        "duo_request",
+        otel.kind = "client",
+        http.method = %request.method(),
+        http.url = %format_args!("{}://{}", url.scheme(), url.host_str().unwrap_or_default()),
+        http.route = url.path(),
+        duo.stat = Empty,
+        duo.error_code = Empty,
+    )
+}
+
+pub(crate) fn record_response<T>(response: &DuoResponse<T>) {
+    let span = Span::current();
+
+    match response {
+        DuoResponse::Ok { .. } => {
+            span.record("duo.stat", "OK");
+        }
+        DuoResponse::Fail { code, .. } => {
+            span.record("duo.stat", "FAIL");
+            span.record("duo.error_code", code);
+        }
+        DuoResponse::Unexpected(stat) => {
+            span.record("duo.stat", stat.as_str());
+        }
+    }
+}
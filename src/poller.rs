@@ -0,0 +1,199 @@
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet, VecDeque},
+    sync::{Arc, Mutex, MutexGuard, PoisonError},
+    time::Duration,
+};
+
+use futures_util::{
+    future::{self, Either},
+    stream::FuturesUnordered,
+    StreamExt,
+};
+use tokio::{
+    sync::{oneshot, Notify},
+    time::Instant,
+};
+
+use super::{
+    client::{DuoClient, DuoClientInner},
+    errors::Error,
+    request::RequestContext,
+    transaction::{jitter, PollStrategy},
+    types::AuthStatusResponse,
+};
+
+/// How many `auth_status` requests the shared poller has in flight at most
+/// under [`PollStrategy::Interval`].
+pub(crate) const SHARED_POLL_CONCURRENCY: usize = 16;
+
+type StatusResult = Result<AuthStatusResponse, Arc<Error>>;
+
+#[derive(Default)]
+struct State {
+    waiters: HashMap<String, Vec<oneshot::Sender<StatusResult>>>,
+    running: bool,
+}
+
+/// Polls the transactions of all waiting callers from a single task, so a
+/// login storm does not turn into as many independent polling loops.
+///
+/// Transactions due for a poll are requested in turn. The delay between two
+/// polls of a transaction is spent in a queue ordered by due time, not in a
+/// request slot, so a transaction waiting out its delay never holds up
+/// another. Callers waiting on the same txid share its requests.
+///
+/// Under [`PollStrategy::Interval`], at most [`SHARED_POLL_CONCURRENCY`]
+/// requests are in flight. Under [`PollStrategy::LongPoll`] there is no such
+/// cap: Duo holds each `auth_status` open until the transaction changes, so
+/// capping them would leave every transaction beyond the cap unpolled until
+/// one of the held ones is answered. Each pending transaction then has one
+/// request open, as with polling per wait, and
+/// [`DuoClientBuilder::concurrency_limit`](crate::DuoClientBuilder::concurrency_limit)
+/// is the way to bound them.
+#[derive(Default)]
+pub(crate) struct StatusPoller {
+    state: Mutex<State>,
+    subscribed: Notify,
+}
+
+impl StatusPoller {
+    fn state(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Waits for the final status of `txid`, starting the poll task if it is
+    /// not running.
+    pub(crate) async fn wait(
+        client: Arc<DuoClientInner>,
+        txid: String,
+    ) -> Result<AuthStatusResponse, Error> {
+        let (sender, receiver) = oneshot::channel();
+
+        {
+            let mut state = client.poller.state();
+            state.waiters.entry(txid).or_default().push(sender);
+            if !state.running {
+                state.running = true;
+                tokio::spawn(Self::run(Arc::clone(&client)));
+            }
+        }
+        client.poller.subscribed.notify_one();
+
+        receiver
+            .await
+            .map_err(|_| Error::unspecified("status poller stopped"))?
+            .map_err(Error::Shared)
+    }
+
+    async fn run(client: Arc<DuoClientInner>) {
+        let poller = &client.poller;
+        let limit = match client.poll_strategy {
+            PollStrategy::Interval(_) => SHARED_POLL_CONCURRENCY,
+            PollStrategy::LongPoll { .. } => usize::MAX,
+        };
+        let mut ready = VecDeque::new();
+        let mut delayed = BinaryHeap::new();
+        let mut known = HashSet::new();
+        let mut fetches = FuturesUnordered::new();
+
+        loop {
+            {
+                let mut state = poller.state();
+                // Nobody waits for these anymore
+                state.waiters.retain(|_, senders| {
+                    senders.retain(|sender| !sender.is_closed());
+                    !senders.is_empty()
+                });
+                ready.retain(|txid| state.waiters.contains_key(txid));
+                delayed.retain(|Reverse((_, txid))| state.waiters.contains_key(txid));
+                known.retain(|txid| state.waiters.contains_key(txid));
+
+                if state.waiters.is_empty() && fetches.is_empty() {
+                    state.running = false;
+                    return;
+                }
+
+                for txid in state.waiters.keys() {
+                    if known.insert(txid.clone()) {
+                        ready.push_back(txid.clone());
+                    }
+                }
+            }
+
+            let now = Instant::now();
+            while delayed
+                .peek()
+                .map_or(false, |Reverse((due, _))| *due <= now)
+            {
+                if let Some(Reverse((_, txid))) = delayed.pop() {
+                    ready.push_back(txid);
+                }
+            }
+
+            while fetches.len() < limit {
+                let Some(txid) = ready.pop_front() else {
+                    break;
+                };
+                fetches.push(Self::fetch(Arc::clone(&client), txid));
+            }
+
+            let next_due = delayed.peek().map(|Reverse((due, _))| *due);
+            let woken = async {
+                let subscribed = poller.subscribed.notified();
+                let due = async {
+                    match next_due {
+                        Some(due) => tokio::time::sleep_until(due).await,
+                        None => future::pending().await,
+                    }
+                };
+                futures_util::pin_mut!(subscribed, due);
+                future::select(subscribed, due).await;
+            };
+            futures_util::pin_mut!(woken);
+
+            if fetches.is_empty() {
+                woken.await;
+                continue;
+            }
+
+            let (txid, result, elapsed) = match future::select(fetches.next(), woken).await {
+                Either::Left((Some(fetched), _)) => fetched,
+                Either::Left((None, _)) | Either::Right(_) => continue,
+            };
+
+            let pending = match &result {
+                Ok(status) => status.ready().is_none(),
+                Err(err) => err.is_rate_limited(),
+            };
+            if pending {
+                let delay = client.poll_strategy.delay(elapsed);
+                let delay = jitter(&client, delay, &txid);
+                if delay.is_zero() {
+                    ready.push_back(txid);
+                } else {
+                    delayed.push(Reverse((Instant::now() + delay, txid)));
+                }
+                continue;
+            }
+
+            known.remove(&txid);
+            let senders = poller.state().waiters.remove(&txid).unwrap_or_default();
+            let result = result.map_err(Arc::new);
+            for sender in senders {
+                let _ = sender.send(result.clone());
+            }
+        }
+    }
+
+    /// Requests the status of `txid`, returning how long the request took.
+    async fn fetch(
+        client: Arc<DuoClientInner>,
+        txid: String,
+    ) -> (String, Result<AuthStatusResponse, Error>, Duration) {
+        let started = Instant::now();
+        let result =
+            DuoClient::request_auth_status(client, &txid, &RequestContext::default()).await;
+        (txid, result, started.elapsed())
+    }
+}
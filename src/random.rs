@@ -0,0 +1,51 @@
+use std::{
+    collections::hash_map::RandomState,
+    hash::{BuildHasher, Hash, Hasher},
+    sync::atomic::{AtomicU64, Ordering},
+};
+
+/// The source of randomness for poll jitter, replaceable in tests to make
+/// delays reproducible.
+pub trait RandomSource: Send + Sync {
+    /// Returns a value in `[0, 1]` for a delay concerning transaction `txid`.
+    fn sample(&self, txid: &str) -> f64;
+}
+
+/// Draws from the random keys of the standard library's hasher, hashed with
+/// the txid.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemRandom;
+
+impl RandomSource for SystemRandom {
+    fn sample(&self, txid: &str) -> f64 {
+        let mut hasher = RandomState::new().build_hasher();
+        txid.hash(&mut hasher);
+        hasher.finish() as f64 / u64::MAX as f64
+    }
+}
+
+/// A seeded sequence, the same for every run with the same seed. Samples
+/// do not depend on the txid, only on the order they are drawn in.
+#[derive(Debug)]
+pub struct SeededRandom(AtomicU64);
+
+impl SeededRandom {
+    pub fn new(seed: u64) -> Self {
+        Self(AtomicU64::new(seed))
+    }
+}
+
+impl RandomSource for SeededRandom {
+    fn sample(&self, _txid: &str) -> f64 {
+        // SplitMix64
+        let state = self
+            .0
+            .fetch_add(0x9E37_79B9_7F4A_7C15, Ordering::Relaxed)
+            .wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^= z >> 31;
+        z as f64 / u64::MAX as f64
+    }
+}
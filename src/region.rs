@@ -0,0 +1,39 @@
+use super::errors::Error;
+
+/// The Duo deployment an account lives in, which determines the domain of its
+/// API host.
+///
+/// Commercial accounts all share the `duosecurity.com` domain, whether they
+/// are hosted in the US, the EU or APAC: the data center is picked by the
+/// account's own `api-XXXXXXXX` subdomain. Federal accounts have their own
+/// domain.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DuoRegion {
+    Commercial,
+    Federal,
+}
+
+impl DuoRegion {
+    pub fn domain(&self) -> &'static str {
+        match self {
+            Self::Commercial => "duosecurity.com",
+            Self::Federal => "duofederal.com",
+        }
+    }
+
+    /// The API domain for `subdomain`, which is the account id with or
+    /// without its `api-` prefix, e.g. `abcdef12`.
+    pub fn api_domain(&self, subdomain: &str) -> Result<String, Error> {
+        let subdomain = subdomain.trim().to_ascii_lowercase();
+        let id = subdomain.strip_prefix("api-").unwrap_or(&subdomain);
+
+        if id.is_empty() || !id.chars().all(|c| c.is_ascii_alphanumeric()) {
+            return Err(Error::InvalidParameter {
+                name: "subdomain",
+                reason: "expected an account id such as 'api-abcdef12'".into(),
+            });
+        }
+
+        Ok(format!("https://api-{}.{}", id, self.domain()))
+    }
+}
@@ -1,11 +1,96 @@
-use std::collections::BTreeMap;
+use std::{collections::BTreeMap, time::Duration};
 
 use chrono::{DateTime, Utc};
-use hmac::{Hmac, Mac};
-use reqwest::{Client, Method, Request, Url};
-use sha1::Sha1;
+use reqwest::{
+    header::{HeaderName, HeaderValue, AUTHORIZATION},
+    Client, Method, Request, Url,
+};
 
-use super::StdError;
+use super::{errors::Error, signing, StdError};
+
+/// Parameters whose values are never logged or captured.
+pub const SENSITIVE_PARAMETERS: &[&str] = &[
+    "code1",
+    "code2",
+    "code3",
+    "passcode",
+    "trusted_device_token",
+];
+
+/// Pairs `pairs` with `None` in place of the values of `sensitive` keys.
+fn redact_pairs<'a, K, V>(
+    pairs: impl IntoIterator<Item = (K, V)> + 'a,
+    sensitive: &'a [&str],
+) -> impl Iterator<Item = (K, Option<V>)> + 'a
+where
+    K: AsRef<str> + 'a,
+    V: 'a,
+{
+    pairs.into_iter().map(move |(k, v)| {
+        let v = (!sensitive.contains(&k.as_ref())).then_some(v);
+        (k, v)
+    })
+}
+
+/// Form-encodes `pairs`, with the values of `sensitive` keys shown as `***`.
+fn serialize_redacted_pairs<K, V>(
+    pairs: impl IntoIterator<Item = (K, V)>,
+    sensitive: &[&str],
+) -> String
+where
+    K: AsRef<str>,
+    V: AsRef<str>,
+{
+    redact_pairs(pairs, sensitive)
+        .map(|(k, v)| {
+            let v = v.map_or_else(
+                || "***".into(),
+                |v| urlencoding::encode(v.as_ref()).into_owned(),
+            );
+            format!("{}={}", urlencoding::encode(k.as_ref()), v)
+        })
+        .collect::<Vec<String>>()
+        .join("&")
+}
+
+#[cfg(feature = "capture-bodies")]
+pub(crate) fn redact_serialized(parameters_str: &str, sensitive: &[&str]) -> String {
+    serialize_redacted_pairs(
+        url::form_urlencoded::parse(parameters_str.as_bytes()),
+        sensitive,
+    )
+}
+
+/// Per-request timeouts, by how long Duo may legitimately take to answer.
+#[derive(Clone, Copy, Debug)]
+pub struct Timeouts {
+    /// `ping`, `check`, `preauth`, enrollment and asynchronous `auth`, 10
+    /// seconds by default
+    pub fast: Duration,
+    /// Synchronous `auth`, which waits for the user to respond, and the
+    /// long-polling `auth_status`, 90 seconds by default
+    pub slow: Duration,
+}
+
+impl Default for Timeouts {
+    fn default() -> Self {
+        Self {
+            fast: Duration::from_secs(10),
+            slow: Duration::from_secs(90),
+        }
+    }
+}
+
+/// What a call does when the client already has as many requests in flight
+/// as [`DuoClientBuilder::concurrency_limit`](crate::DuoClientBuilder::concurrency_limit)
+/// allows.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum WhenLimited {
+    /// Waits until another request completes
+    Wait,
+    /// Fails with [`Error::ConcurrencyLimited`]
+    Fail,
+}
 
 #[derive(Default)]
 pub struct Parameters(BTreeMap<String, String>);
@@ -21,6 +106,19 @@ impl Parameters {
         }
     }
 
+    /// The parameters with the values of [`SENSITIVE_PARAMETERS`] replaced
+    /// by `***`, safe to log.
+    pub fn redacted(&self) -> BTreeMap<String, String> {
+        self.redacted_with(SENSITIVE_PARAMETERS)
+    }
+
+    /// Like [`Parameters::redacted`], with `sensitive` as the keys to redact.
+    pub fn redacted_with(&self, sensitive: &[&str]) -> BTreeMap<String, String> {
+        redact_pairs(&self.0, sensitive)
+            .map(|(k, v)| (k.clone(), v.map_or_else(|| "***".into(), Clone::clone)))
+            .collect()
+    }
+
     pub fn serialize(&self) -> String {
         self.0
             .iter()
@@ -28,6 +126,65 @@ impl Parameters {
             .collect::<Vec<String>>()
             .join("&")
     }
+
+    /// Whether [`Parameters::serialize`] yields what Duo expects to be signed:
+    /// keys in strictly ascending order, and keys and values percent-encoded
+    /// per RFC 3986 with uppercase hex digits.
+    pub fn is_canonical(&self) -> bool {
+        fn is_encoded(s: &str) -> bool {
+            let bytes = s.as_bytes();
+            let mut i = 0;
+            while i < bytes.len() {
+                match bytes[i] {
+                    b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => i += 1,
+                    b'%' if bytes.len() > i + 2
+                        && bytes[i + 1..i + 3]
+                            .iter()
+                            .all(|b| matches!(b, b'0'..=b'9' | b'A'..=b'F')) =>
+                    {
+                        i += 3
+                    }
+                    _ => return false,
+                }
+            }
+            true
+        }
+
+        let serialized = self.serialize();
+        let mut previous: Option<&str> = None;
+        for pair in serialized.split('&').filter(|pair| !pair.is_empty()) {
+            let Some((k, v)) = pair.split_once('=') else {
+                return false;
+            };
+            if !is_encoded(k) || !is_encoded(v) || previous.map_or(false, |p| p >= k) {
+                return false;
+            }
+            previous = Some(k);
+        }
+        true
+    }
+
+    /// Like [`Parameters::serialize`], with the values of `sensitive` keys
+    /// replaced by `***`.
+    pub fn serialize_redacted_with(&self, sensitive: &[&str]) -> String {
+        serialize_redacted_pairs(&self.0, sensitive)
+    }
+}
+
+fn check_length(parameters_str: &str, no_body: bool) -> Result<(), Error> {
+    let limit = if no_body {
+        MAX_QUERY_LENGTH
+    } else {
+        MAX_BODY_LENGTH
+    };
+
+    if parameters_str.len() > limit {
+        return Err(Error::ParametersTooLarge {
+            size: parameters_str.len(),
+            limit,
+        });
+    }
+    Ok(())
 }
 
 impl From<Parameters> for BTreeMap<String, String> {
@@ -36,12 +193,101 @@ impl From<Parameters> for BTreeMap<String, String> {
     }
 }
 
+/// How far a request's Date may be from Duo's clock. This is enforced by Duo,
+/// which rejects requests outside of it with code 40105, not by this crate.
+pub const DEFAULT_SIGNATURE_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+pub const DEFAULT_USER_AGENT: &str = concat!("duo-auth-rs/", env!("CARGO_PKG_VERSION"));
+
+pub const DEFAULT_CONTENT_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// Limits on the serialized parameters, checked before signing.
+///
+/// Duo's Auth API only supports signatures over the full parameter string;
+/// the body hash signing of newer APIs is not available for it. The
+/// signature itself has a fixed size, so the limits only guard against
+/// requests that servers and proxies commonly refuse: a query string
+/// beyond 8 KiB for GET requests, or a body beyond 64 KiB otherwise. Duo does
+/// not document limits of its own.
+pub const MAX_QUERY_LENGTH: usize = 8 * 1024;
+pub const MAX_BODY_LENGTH: usize = 64 * 1024;
+
+pub const DEFAULT_CORRELATION_HEADER: &str = "X-Request-Id";
+
+/// Per-call metadata that is attached to a request after it has been signed,
+/// so it never affects the signature.
+#[derive(Clone, Debug, Default)]
+pub struct RequestContext {
+    correlation: Option<(String, String)>,
+    query: Vec<(String, String)>,
+}
+
+impl RequestContext {
+    /// Sends `correlation_id` in the [`DEFAULT_CORRELATION_HEADER`] header.
+    pub fn new<S: Into<String>>(correlation_id: S) -> Self {
+        Self::with_header(DEFAULT_CORRELATION_HEADER, correlation_id)
+    }
+
+    pub fn with_header<H: Into<String>, S: Into<String>>(header: H, correlation_id: S) -> Self {
+        Self {
+            correlation: Some((header.into(), correlation_id.into())),
+            query: Vec::new(),
+        }
+    }
+
+    /// Appends a query parameter that is not signed, e.g. for a proxy that
+    /// routes by it. Duo includes every query parameter it receives when
+    /// checking the signature, so the proxy must strip it before forwarding.
+    /// Signed parameters always go through [`Parameters`].
+    ///
+    /// Only GET and HEAD requests carry their parameters in the query, so
+    /// other requests fail with [`Error::InvalidParameter`] rather than mix
+    /// unsigned query parameters with a signed body.
+    pub fn with_query<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Self {
+        self.query.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn correlation_id(&self) -> Option<&str> {
+        self.correlation.as_ref().map(|(_, id)| id.as_str())
+    }
+
+    pub(crate) fn apply(&self, request: &mut Request) -> Result<(), Error> {
+        if !self.query.is_empty() {
+            if !matches!(*request.method(), Method::GET | Method::HEAD) {
+                return Err(Error::InvalidParameter {
+                    name: "query",
+                    reason: format!("not allowed for {} requests", request.method()),
+                });
+            }
+
+            request
+                .url_mut()
+                .query_pairs_mut()
+                .extend_pairs(&self.query);
+        }
+
+        if let Some((header, id)) = &self.correlation {
+            request.headers_mut().insert(
+                HeaderName::try_from(header.as_str()).map_err(Error::unspecified)?,
+                HeaderValue::try_from(id.as_str()).map_err(Error::unspecified)?,
+            );
+        }
+
+        Ok(())
+    }
+}
+
 pub struct DuoRequest {
     url: Url,
     method: Method,
     path: String,
     date: DateTime<Utc>,
     parameters: Parameters,
+    content_type: String,
+    user_agent: Option<String>,
+    signed_headers: Vec<(String, String)>,
+    signing_host: Option<String>,
 }
 
 impl DuoRequest {
@@ -52,28 +298,126 @@ impl DuoRequest {
             path: path.into(),
             date: Utc::now(),
             parameters,
+            content_type: DEFAULT_CONTENT_TYPE.into(),
+            user_agent: Some(DEFAULT_USER_AGENT.into()),
+            signed_headers: Vec::new(),
+            signing_host: None,
         }
     }
 
+    /// Sends a header that is covered by the signature, for backends that
+    /// sign more than the Date header. See
+    /// [`canonical_string_with_headers`](signing::canonical_string_with_headers)
+    /// for how it is signed.
+    pub fn with_signed_header<N: Into<String>, V: Into<String>>(
+        mut self,
+        name: N,
+        value: V,
+    ) -> Self {
+        self.signed_headers.push((name.into(), value.into()));
+        self
+    }
+
+    /// Signs the request for `host` instead of the host of its URL. Duo
+    /// checks the signature against the host it was reached at, so this is
+    /// only of use when the request reaches Duo under `host` regardless of
+    /// its URL, e.g. through a custom transport.
+    pub fn with_signing_host<S: Into<String>>(mut self, host: S) -> Self {
+        self.signing_host = Some(host.into());
+        self
+    }
+
+    /// Overrides the User-Agent, which defaults to [`DEFAULT_USER_AGENT`], or
+    /// omits it when `None`.
+    pub fn with_user_agent(mut self, user_agent: Option<String>) -> Self {
+        self.user_agent = user_agent;
+        self
+    }
+
+    /// Overrides the time the request is signed with, e.g. to compensate for
+    /// a local clock that is off.
+    pub fn with_date(mut self, date: DateTime<Utc>) -> Self {
+        self.date = date;
+        self
+    }
+
+    /// The time the request is signed with. A request signed here but sent
+    /// elsewhere must carry exactly this time, formatted by
+    /// [`DuoRequest::date_header`], as its Date header.
+    pub fn date(&self) -> DateTime<Utc> {
+        self.date
+    }
+
+    pub fn date_header(&self) -> String {
+        self.date.to_rfc2822()
+    }
+
+    /// Overrides the Content-Type of the request body. The content type is
+    /// not signed, so this does not affect the signature.
+    pub fn with_content_type<S: Into<String>>(mut self, content_type: S) -> Self {
+        self.content_type = content_type.into();
+        self
+    }
+
+    /// The latest time this request can be sent at before Duo considers its
+    /// signature stale, assuming the [`DEFAULT_SIGNATURE_WINDOW`].
+    pub fn valid_until(&self) -> DateTime<Utc> {
+        self.valid_until_within(DEFAULT_SIGNATURE_WINDOW)
+    }
+
+    pub fn valid_until_within(&self, window: Duration) -> DateTime<Utc> {
+        chrono::Duration::from_std(window)
+            .ok()
+            .and_then(|window| self.date.checked_add_signed(window))
+            .unwrap_or(DateTime::<Utc>::MAX_UTC)
+    }
+
+    /// The parameters sent with this request, with secrets redacted.
+    pub fn parameters(&self) -> BTreeMap<String, String> {
+        self.parameters.redacted()
+    }
+
+    /// The body of a POST or the query string of a GET exactly as it is
+    /// sent and signed, except that secrets are redacted, e.g. for audit
+    /// logs.
+    pub fn wire_parameters(&self) -> String {
+        self.wire_parameters_with(SENSITIVE_PARAMETERS)
+    }
+
+    /// Like [`DuoRequest::wire_parameters`], with `sensitive` as the keys to
+    /// redact.
+    pub fn wire_parameters_with(&self, sensitive: &[&str]) -> String {
+        self.parameters.serialize_redacted_with(sensitive)
+    }
+
     pub fn build(&self, client: &Client, ikey: &str, skey: &str) -> Result<Request, StdError> {
         let no_body = matches!(self.method, Method::GET | Method::HEAD);
 
         let parameters_str = self.parameters.serialize();
+        check_length(&parameters_str, no_body)?;
         let mut url = self.url.clone();
         url.set_path(&self.path);
         if no_body {
             url.set_query(Some(&parameters_str))
         }
 
-        let signature = self.build_signature(skey, &parameters_str)?;
+        let mut authorization =
+            HeaderValue::try_from(self.authorization_for(ikey, skey, &parameters_str)?)?;
+        authorization.set_sensitive(true);
         let mut rb = client
             .request(self.method.clone(), url)
-            .basic_auth(ikey.clone(), Some(signature))
+            .header(AUTHORIZATION, authorization)
             .header("Date", self.date.to_rfc2822());
+        if let Some(user_agent) = &self.user_agent {
+            rb = rb.header("User-Agent", user_agent)
+        }
+        for (name, value) in &self.signed_headers {
+            rb = rb.header(name.as_str(), value.as_str());
+        }
 
         if !no_body {
             rb = rb
-                .header("Content-Type", "application/x-www-form-urlencoded")
+                .header("Content-Type", &self.content_type)
                 .body(parameters_str)
         }
 
@@ -84,6 +428,7 @@ impl DuoRequest {
         let no_body = matches!(self.method, Method::GET | Method::HEAD);
 
         let parameters_str = self.parameters.serialize();
+        check_length(&parameters_str, no_body)?;
         let mut url = self.url.clone();
         url.set_path(&self.path);
         if no_body {
@@ -92,38 +437,105 @@ impl DuoRequest {
 
         let mut rb = client
             .request(self.method.clone(), url)
-            .header("Date", self.date.to_rfc2822())
-            .header(
-                "User-Agent",
-                concat!("duo-auth-rs/", env!("CARGO_PKG_VERSION")),
-            );
+            .header("Date", self.date.to_rfc2822());
+
+        if let Some(user_agent) = &self.user_agent {
+            rb = rb.header("User-Agent", user_agent)
+        }
 
         if !no_body {
             rb = rb
-                .header("Content-Type", "application/x-www-form-urlencoded")
+                .header("Content-Type", &self.content_type)
                 .body(parameters_str)
         }
 
         rb.build().map_err(|e| e.into())
     }
 
-    fn build_signature(&self, skey: &str, parameters_str: &str) -> Result<String, StdError> {
-        let domain = self.url.host_str().unwrap().to_string();
+    /// Renders the request as a `curl` command that sends it exactly as
+    /// `build` would, e.g. to hand to Duo support when a signature is
+    /// rejected. The command contains the signature but not the skey. The
+    /// parameters are not redacted, so a passcode is shown as is.
+    #[cfg(feature = "debug-curl")]
+    pub fn to_curl(&self, ikey: &str, skey: &str) -> Result<String, StdError> {
+        fn quote(value: &str) -> String {
+            format!("'{}'", value.replace('\'', r"'\''"))
+        }
 
-        let payload = &[
-            self.date.to_rfc2822(),
-            self.method.to_string().to_uppercase(),
-            domain,
-            self.path.clone(),
-            parameters_str.into(),
-        ]
-        .join("\n");
+        let no_body = matches!(self.method, Method::GET | Method::HEAD);
+        let parameters_str = self.parameters.serialize();
 
-        let mut signer = Hmac::<Sha1>::new_from_slice(skey.as_bytes())?;
-        signer.update(payload.as_bytes());
+        let mut url = self.url.clone();
+        url.set_path(&self.path);
+        if no_body {
+            url.set_query(Some(&parameters_str))
+        }
 
-        let signature = hex::encode(signer.finalize().into_bytes());
+        let mut command = vec![
+            "curl".to_string(),
+            "-X".into(),
+            self.method.to_string(),
+            quote(url.as_str()),
+            "-H".into(),
+            quote(&format!("Date: {}", self.date.to_rfc2822())),
+            "-H".into(),
+            quote(&format!(
+                "Authorization: {}",
+                self.authorization_for(ikey, skey, &parameters_str)?
+            )),
+        ];
+        if let Some(user_agent) = &self.user_agent {
+            command.extend(["-H".into(), quote(&format!("User-Agent: {}", user_agent))]);
+        }
+        for (name, value) in &self.signed_headers {
+            command.extend(["-H".into(), quote(&format!("{}: {}", name, value))]);
+        }
+        if !no_body {
+            command.extend([
+                "-H".into(),
+                quote(&format!("Content-Type: {}", self.content_type)),
+                "--data-raw".into(),
+                quote(&parameters_str),
+            ]);
+        }
 
-        Ok(signature)
+        Ok(command.join(" "))
+    }
+
+    /// The Authorization header value `build` signs the request with, i.e.
+    /// `Basic base64(ikey:signature)`.
+    pub fn authorization_value(&self, ikey: &str, skey: &str) -> Result<String, StdError> {
+        self.authorization_for(ikey, skey, &self.parameters.serialize())
+    }
+
+    fn authorization_for(
+        &self,
+        ikey: &str,
+        skey: &str,
+        parameters_str: &str,
+    ) -> Result<String, StdError> {
+        let signature = self.build_signature(skey, parameters_str)?;
+        Ok(signing::basic_authorization(ikey, &signature))
+    }
+
+    fn build_signature(&self, skey: &str, parameters_str: &str) -> Result<String, StdError> {
+        let domain = self
+            .signing_host
+            .as_deref()
+            .or_else(|| self.url.host_str())
+            .ok_or_else(|| Error::InvalidApiDomain {
+                domain: self.url.to_string(),
+                cause: "no domain in url".into(),
+            })?;
+
+        Ok(signing::sign_with_headers(
+            self.method.as_str(),
+            domain,
+            &self.path,
+            &self.date,
+            parameters_str,
+            &self.signed_headers,
+            skey,
+        ))
     }
 }
@@ -1,24 +1,89 @@
-use serde::Deserialize;
+use std::time::Duration;
+
+use chrono::{DateTime, Utc};
+use reqwest::{
+    header::{HeaderMap, DATE, RETRY_AFTER},
+    Response,
+};
+use serde::{de::DeserializeOwned, Deserialize, Deserializer, Serialize};
+use serde_json::Value;
 
 use super::errors::Error;
 
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Serialize)]
 #[serde(tag = "stat", rename_all = "SCREAMING_SNAKE_CASE")]
 pub enum DuoResponse<T> {
     Ok {
         response: T,
+        /// Paging details, only sent by endpoints returning lists
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<PageMetadata>,
     },
     Fail {
         code: u64,
         message: String,
         message_detail: Option<String>,
     },
+    /// A `stat` other than `OK` or `FAIL`, e.g. from a proxy in between.
+    /// Cannot be serialized.
+    #[serde(skip_serializing)]
+    Unexpected(String),
+}
+
+impl<'de, T: DeserializeOwned> Deserialize<'de> for DuoResponse<T> {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        use serde::de::Error as _;
+
+        #[derive(Deserialize)]
+        #[serde(tag = "stat", rename_all = "SCREAMING_SNAKE_CASE")]
+        enum Known<T> {
+            Ok {
+                response: T,
+                #[serde(default)]
+                metadata: Option<PageMetadata>,
+            },
+            Fail {
+                code: u64,
+                message: String,
+                message_detail: Option<String>,
+            },
+        }
+
+        let value = Value::deserialize(deserializer)?;
+        let stat = match value.get("stat") {
+            Some(Value::String(stat)) if stat == "OK" || stat == "FAIL" => None,
+            Some(Value::String(stat)) => Some(stat.clone()),
+            Some(stat) => Some(stat.to_string()),
+            None => return Err(D::Error::missing_field("stat")),
+        };
+        if let Some(stat) = stat {
+            return Ok(Self::Unexpected(stat));
+        }
+
+        Ok(match Known::deserialize(value).map_err(D::Error::custom)? {
+            Known::Ok { response, metadata } => Self::Ok { response, metadata },
+            Known::Fail {
+                code,
+                message,
+                message_detail,
+            } => Self::Fail {
+                code,
+                message,
+                message_detail,
+            },
+        })
+    }
 }
 
 impl<T> DuoResponse<T> {
     pub(crate) fn ok(self) -> Result<T, Error> {
         match self {
-            DuoResponse::Ok { response } => Ok(response),
+            DuoResponse::Ok { response, .. } => Ok(response),
+            DuoResponse::Fail {
+                code: code @ (40102 | 40103),
+                message,
+                ..
+            } => Err(Error::InvalidCredentials { code, message }),
             DuoResponse::Fail {
                 code,
                 message,
@@ -27,7 +92,183 @@ impl<T> DuoResponse<T> {
                 code,
                 message,
                 message_detail,
+                captured: None,
+            }),
+            DuoResponse::Unexpected(stat) => Err(Error::UnexpectedStat { stat }),
+        }
+    }
+}
+
+impl<T> DuoResponse<Vec<T>> {
+    /// Turns the envelope into a page, or into an error for a failure.
+    pub fn into_page(self) -> Result<Page<T>, Error> {
+        match self {
+            DuoResponse::Ok { response, metadata } => Ok(Page {
+                items: response,
+                metadata,
+            }),
+            DuoResponse::Fail { .. } | DuoResponse::Unexpected(_) => self.ok().map(|items| Page {
+                items,
+                metadata: None,
             }),
         }
     }
 }
+
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PageMetadata {
+    /// Offset of the next page, absent on the last one
+    pub next_offset: Option<u64>,
+    pub prev_offset: Option<u64>,
+    pub total_objects: Option<u64>,
+}
+
+/// One page of a list endpoint.
+#[derive(Clone, Debug)]
+pub struct Page<T> {
+    pub items: Vec<T>,
+    /// Absent if the endpoint does not paginate
+    pub metadata: Option<PageMetadata>,
+}
+
+impl<T> Page<T> {
+    pub fn next_offset(&self) -> Option<u64> {
+        self.metadata
+            .as_ref()
+            .and_then(|metadata| metadata.next_offset)
+    }
+}
+
+/// A response envelope along with details about the response.
+#[derive(Debug)]
+pub struct RawResponse<T> {
+    pub body: DuoResponse<T>,
+    pub meta: ResponseMeta,
+}
+
+#[derive(Clone, Debug)]
+pub struct ResponseMeta {
+    /// From sending the request until the whole body was read
+    pub round_trip: Duration,
+    /// The `Date` header of the response
+    pub server_date: Option<DateTime<Utc>>,
+    /// The time Duo reports having spent on the request, from an `elapsed`
+    /// field of the body in seconds, or a `dur` in the `Server-Timing` header
+    /// in milliseconds. Duo documents neither, so this is usually absent.
+    pub server_elapsed: Option<Duration>,
+}
+
+impl ResponseMeta {
+    pub(crate) fn new(round_trip: Duration, headers: &HeaderMap, body: &[u8]) -> Self {
+        #[derive(Deserialize)]
+        struct Timing {
+            elapsed: Option<f64>,
+        }
+
+        let server_date = headers
+            .get(DATE)
+            .and_then(|date| date.to_str().ok())
+            .and_then(|date| DateTime::parse_from_rfc2822(date).ok())
+            .map(|date| date.with_timezone(&Utc));
+
+        let body_elapsed = serde_json::from_slice::<Timing>(body)
+            .ok()
+            .and_then(|timing| timing.elapsed)
+            .and_then(|secs| Duration::try_from_secs_f64(secs).ok());
+        let header_elapsed = headers
+            .get("Server-Timing")
+            .and_then(|timing| timing.to_str().ok())
+            .and_then(|timing| {
+                timing
+                    .split([',', ';'])
+                    .find_map(|param| param.trim().strip_prefix("dur="))
+            })
+            .and_then(|millis| millis.parse::<f64>().ok())
+            .and_then(|millis| Duration::try_from_secs_f64(millis / 1000.0).ok());
+
+        Self {
+            round_trip,
+            server_date,
+            server_elapsed: body_elapsed.or(header_elapsed),
+        }
+    }
+}
+
+/// The largest response body read by default. Responses are buffered and
+/// deserialized in full, so this bounds the memory a single response may
+/// take, e.g. for a preauth listing an unusually large number of devices.
+pub const MAX_RESPONSE_BYTES: usize = 1024 * 1024;
+
+/// Reads the whole body, failing with [`Error::ResponseTooLarge`] as soon as
+/// it is known to exceed `limit`.
+pub(crate) async fn read_body(mut response: Response, limit: usize) -> Result<Vec<u8>, Error> {
+    let too_large = || Error::ResponseTooLarge { limit };
+
+    if response
+        .content_length()
+        .map_or(false, |length| length > limit as u64)
+    {
+        return Err(too_large());
+    }
+
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(Error::unspecified)? {
+        if body.len() + chunk.len() > limit {
+            return Err(too_large());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(body)
+}
+
+/// Reads `Retry-After` in either its delta-seconds or its HTTP-date form. A
+/// date in the past yields a zero duration.
+pub(crate) fn retry_after(headers: &HeaderMap, now: DateTime<Utc>) -> Option<Duration> {
+    let value = headers.get(RETRY_AFTER)?.to_str().ok()?.trim();
+
+    if let Ok(secs) = value.parse::<u64>() {
+        return Some(Duration::from_secs(secs));
+    }
+
+    let date = DateTime::parse_from_rfc2822(value).ok()?;
+    Some(
+        date.with_timezone(&Utc)
+            .signed_duration_since(now)
+            .to_std()
+            .unwrap_or(Duration::ZERO),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::TimeZone;
+    use reqwest::header::HeaderValue;
+
+    use super::*;
+
+    fn headers(value: &'static str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(RETRY_AFTER, HeaderValue::from_static(value));
+        headers
+    }
+
+    #[test]
+    fn retry_after_forms() {
+        let now = Utc.with_ymd_and_hms(2015, 10, 21, 7, 28, 0).unwrap();
+
+        assert_eq!(
+            retry_after(&headers("120"), now),
+            Some(Duration::from_secs(120))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:28:30 GMT"), now),
+            Some(Duration::from_secs(30))
+        );
+        assert_eq!(
+            retry_after(&headers("Wed, 21 Oct 2015 07:27:00 GMT"), now),
+            Some(Duration::ZERO)
+        );
+        assert_eq!(retry_after(&headers("soon"), now), None);
+        assert_eq!(retry_after(&HeaderMap::new(), now), None);
+    }
+}
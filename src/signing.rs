@@ -0,0 +1,141 @@
+//! Duo request signing, independent of the HTTP client.
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{DateTime, Utc};
+use hmac::{Hmac, Mac};
+use sha1::Sha1;
+
+use super::request::Parameters;
+
+/// Builds the string Duo signs requests over. `parameters` must be serialized
+/// the way they are sent: sorted by key and URL-encoded.
+pub fn canonical_string(
+    method: &str,
+    host: &str,
+    path: &str,
+    date: &DateTime<Utc>,
+    parameters: &str,
+) -> String {
+    canonical_string_with_headers(method, host, path, date, parameters, &[])
+}
+
+/// Like [`canonical_string`], with a `name:value` line appended for each of
+/// `headers`, ordered by lowercased name, so that they are covered by the
+/// signature as well. Without headers, this is the same as
+/// [`canonical_string`].
+pub fn canonical_string_with_headers(
+    method: &str,
+    host: &str,
+    path: &str,
+    date: &DateTime<Utc>,
+    parameters: &str,
+    headers: &[(String, String)],
+) -> String {
+    let mut headers: Vec<_> = headers
+        .iter()
+        .map(|(name, value)| format!("{}:{}", name.to_lowercase(), value.trim()))
+        .collect();
+    headers.sort();
+
+    [
+        date.to_rfc2822(),
+        method.to_uppercase(),
+        host.to_lowercase(),
+        path.to_string(),
+        parameters.to_string(),
+    ]
+    .into_iter()
+    .chain(headers)
+    .collect::<Vec<_>>()
+    .join("\n")
+}
+
+/// Computes the hex-encoded HMAC-SHA1 signature used as the password of the
+/// request's basic authentication.
+pub fn sign(
+    method: &str,
+    host: &str,
+    path: &str,
+    date: &DateTime<Utc>,
+    parameters: &str,
+    skey: &str,
+) -> String {
+    sign_with_headers(method, host, path, date, parameters, &[], skey)
+}
+
+/// Like [`sign`], over [`canonical_string_with_headers`].
+pub fn sign_with_headers(
+    method: &str,
+    host: &str,
+    path: &str,
+    date: &DateTime<Utc>,
+    parameters: &str,
+    headers: &[(String, String)],
+    skey: &str,
+) -> String {
+    let payload = canonical_string_with_headers(method, host, path, date, parameters, headers);
+
+    let mut signer =
+        Hmac::<Sha1>::new_from_slice(skey.as_bytes()).expect("HMAC accepts keys of any length");
+    signer.update(payload.as_bytes());
+
+    hex::encode(signer.finalize().into_bytes())
+}
+
+/// The Authorization header value for a request signed with `signature`.
+pub fn basic_authorization(ikey: &str, signature: &str) -> String {
+    format!(
+        "Basic {}",
+        STANDARD.encode(format!("{}:{}", ikey, signature))
+    )
+}
+
+/// The headers that authenticate a request to Duo.
+#[derive(Clone, Debug)]
+pub struct SignedHeaders {
+    pub authorization: String,
+    /// `date` in RFC 2822 form, which must be sent as the Date header
+    pub date: String,
+}
+
+/// Signs requests that are sent by other means than this crate's client.
+#[derive(Clone)]
+pub struct DuoSigner {
+    ikey: String,
+    skey: String,
+}
+
+impl DuoSigner {
+    pub fn new<I: Into<String>, S: Into<String>>(ikey: I, skey: S) -> Self {
+        Self {
+            ikey: ikey.into(),
+            skey: skey.into(),
+        }
+    }
+
+    /// Computes the headers for a request with `parameters`, which must then
+    /// be sent in the body or query exactly as [`Parameters::serialize`]
+    /// produces them.
+    pub fn sign(
+        &self,
+        method: &str,
+        host: &str,
+        path: &str,
+        date: &DateTime<Utc>,
+        parameters: &Parameters,
+    ) -> SignedHeaders {
+        let signature = sign(
+            method,
+            host,
+            path,
+            date,
+            &parameters.serialize(),
+            &self.skey,
+        );
+
+        SignedHeaders {
+            authorization: basic_authorization(&self.ikey, &signature),
+            date: date.to_rfc2822(),
+        }
+    }
+}
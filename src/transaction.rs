@@ -0,0 +1,373 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use futures_util::{stream, Stream};
+
+use super::{
+    client::{DuoClient, DuoClientInner},
+    errors::Error,
+    poller::StatusPoller,
+    request::RequestContext,
+    types::{AuthStatus, AuthStatusResponse},
+};
+
+pub const DEFAULT_POLL_JITTER: f64 = 0.1;
+
+/// How waiting for a transaction paces its `auth_status` requests.
+#[derive(Clone, Copy, Debug)]
+pub enum PollStrategy {
+    /// Polls again as soon as a response arrives, relying on Duo holding
+    /// `auth_status` open until the transaction changes. Responses arriving
+    /// faster than `min_interval` are treated as coming from a backend that
+    /// does not long-poll, and the next request is delayed accordingly.
+    LongPoll { min_interval: Duration },
+    /// Sleeps for a fixed interval between requests.
+    Interval(Duration),
+}
+
+impl PollStrategy {
+    pub(crate) fn delay(&self, elapsed: Duration) -> Duration {
+        match self {
+            Self::LongPoll { min_interval } => min_interval.saturating_sub(elapsed),
+            Self::Interval(interval) => *interval,
+        }
+    }
+}
+
+/// Spreads `delay` by up to `fraction` in either direction, so concurrent
+/// waits do not poll in lockstep. The randomness comes from the client's
+/// [`RandomSource`](crate::random::RandomSource).
+pub(crate) fn jitter(client: &DuoClientInner, delay: Duration, txid: &str) -> Duration {
+    let fraction = client.poll_jitter;
+    if fraction <= 0.0 || delay.is_zero() {
+        return delay;
+    }
+
+    // Uniform in [-1, 1]
+    let unit = client.random.sample(txid).clamp(0.0, 1.0) * 2.0 - 1.0;
+    delay.mul_f64((1.0 + unit * fraction.min(1.0)).max(0.0))
+}
+
+impl Default for PollStrategy {
+    fn default() -> Self {
+        Self::LongPoll {
+            min_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum PollResult {
+    Pending,
+    Done(AuthStatusResponse),
+}
+
+#[derive(Clone, Debug)]
+pub struct TransactionSnapshot {
+    pub txid: String,
+    pub age: Duration,
+    /// The status seen by the most recent poll, if any
+    pub last_status: Option<AuthStatus>,
+}
+
+struct TrackedTransaction {
+    txid: String,
+    started: Instant,
+    last_status: Option<AuthStatus>,
+}
+
+/// Keeps track of the transaction handles that are alive for a client.
+#[derive(Default)]
+pub(crate) struct TransactionRegistry {
+    next_id: AtomicU64,
+    transactions: Mutex<HashMap<u64, TrackedTransaction>>,
+}
+
+impl TransactionRegistry {
+    fn transactions(&self) -> MutexGuard<'_, HashMap<u64, TrackedTransaction>> {
+        self.transactions
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn register(&self, txid: &str) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        self.transactions().insert(
+            id,
+            TrackedTransaction {
+                txid: txid.to_string(),
+                started: Instant::now(),
+                last_status: None,
+            },
+        );
+        id
+    }
+
+    fn update(&self, id: u64, status: AuthStatus) {
+        if let Some(transaction) = self.transactions().get_mut(&id) {
+            transaction.last_status = Some(status);
+        }
+    }
+
+    fn remove(&self, id: u64) {
+        self.transactions().remove(&id);
+    }
+
+    pub(crate) fn snapshot(&self) -> Vec<TransactionSnapshot> {
+        self.transactions()
+            .values()
+            .map(|transaction| TransactionSnapshot {
+                txid: transaction.txid.clone(),
+                age: transaction.started.elapsed(),
+                last_status: transaction.last_status,
+            })
+            .collect()
+    }
+}
+
+pub struct AuthTransaction {
+    client: Arc<DuoClientInner>,
+    txid: String,
+    registry_id: Option<u64>,
+    /// Whether the transaction was polled or abandoned
+    handled: AtomicBool,
+}
+
+impl AuthTransaction {
+    pub(crate) fn new(client: Arc<DuoClientInner>, txid: String) -> Self {
+        let registry_id = client
+            .registry
+            .as_ref()
+            .map(|registry| registry.register(&txid));
+
+        Self {
+            client,
+            txid,
+            registry_id,
+            handled: AtomicBool::new(false),
+        }
+    }
+
+    fn mark_handled(&self) {
+        self.handled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn txid(&self) -> &str {
+        &self.txid
+    }
+
+    pub fn status(&self) -> impl Future<Output = Result<AuthStatusResponse, Error>> {
+        self.mark_handled();
+        let this = Arc::clone(&self.client);
+        let txid = self.txid.clone();
+        let registry_id = self.registry_id;
+
+        async move {
+            let status =
+                DuoClient::request_auth_status(this.clone(), &txid, &RequestContext::default())
+                    .await?;
+            if let (Some(registry), Some(id)) = (&this.registry, registry_id) {
+                registry.update(id, status.status);
+            }
+            Ok(status)
+        }
+    }
+
+    /// Checks the status once, for callers that schedule polling themselves.
+    pub fn poll(&self) -> impl Future<Output = Result<PollResult, Error>> {
+        let status = self.status();
+
+        async move {
+            let status = status.await?;
+            Ok(match status.ready() {
+                None => PollResult::Pending,
+                Some(_) => PollResult::Done(status),
+            })
+        }
+    }
+
+    pub async fn wait(self) -> Result<bool, Error> {
+        let status = self.wait_full().await?;
+        Ok(status.ready().unwrap_or_default())
+    }
+
+    /// Like [`AuthTransaction::wait`], but returns the final status response.
+    pub async fn wait_full(self) -> Result<AuthStatusResponse, Error> {
+        let poll_strategy = self.client.poll_strategy;
+        self.wait_with(poll_strategy).await
+    }
+
+    /// Like [`AuthTransaction::wait_full`], but leaves polling to the
+    /// client's shared poller.
+    pub(crate) async fn wait_shared(self) -> Result<AuthStatusResponse, Error> {
+        self.mark_handled();
+        let wait = StatusPoller::wait(Arc::clone(&self.client), self.txid.clone());
+        let status = instrument!(wait, "duo_auth_wait", txid = %self.txid).await?;
+        if let (Some(registry), Some(id)) = (&self.client.registry, self.registry_id) {
+            registry.update(id, status.status);
+        }
+        Ok(status)
+    }
+
+    /// Like [`AuthTransaction::wait`], but `is_terminal` decides which
+    /// statuses end the wait and what they yield, e.g. to stop at `bypass`
+    /// or to count it as a failure. Returning `None` falls back to
+    /// [`AuthStatusResponse::ready`], so a transaction Duo has finished is
+    /// never polled further.
+    pub async fn wait_until<F>(self, is_terminal: F) -> Result<bool, Error>
+    where
+        F: FnMut(&AuthStatusResponse) -> Option<bool>,
+    {
+        let poll_strategy = self.client.poll_strategy;
+        let (ready, _) = self.wait_with_predicate(poll_strategy, is_terminal).await?;
+        Ok(ready)
+    }
+
+    pub(crate) async fn wait_with(
+        self,
+        poll_strategy: PollStrategy,
+    ) -> Result<AuthStatusResponse, Error> {
+        let (_, status) = self
+            .wait_with_predicate(poll_strategy, AuthStatusResponse::ready)
+            .await?;
+        Ok(status)
+    }
+
+    async fn wait_with_predicate<F>(
+        self,
+        poll_strategy: PollStrategy,
+        mut is_terminal: F,
+    ) -> Result<(bool, AuthStatusResponse), Error>
+    where
+        F: FnMut(&AuthStatusResponse) -> Option<bool>,
+    {
+        instrument!(
+            async move {
+                loop {
+                    let started = Instant::now();
+                    let status = self.status().await?;
+                    match is_terminal(&status).or_else(|| status.ready()) {
+                        None => {
+                            let delay = self.next_delay(poll_strategy, started.elapsed());
+                            tokio::time::sleep(delay).await
+                        }
+                        Some(ready) => return Ok((ready, status)),
+                    }
+                }
+            },
+            "duo_auth_wait",
+            txid = %self.txid
+        )
+        .await
+    }
+
+    fn next_delay(&self, poll_strategy: PollStrategy, elapsed: Duration) -> Duration {
+        jitter(&self.client, poll_strategy.delay(elapsed), &self.txid)
+    }
+
+    /// Yields every status seen while polling, ending after the first final
+    /// one or the first error. Useful to observe intermediate states such as
+    /// a phone call being answered.
+    pub fn status_stream(self) -> impl Stream<Item = Result<AuthStatusResponse, Error>> {
+        stream::unfold(Some((self, None)), |state| async move {
+            let (transaction, delay): (Self, Option<Duration>) = state?;
+            if let Some(delay) = delay {
+                tokio::time::sleep(delay).await;
+            }
+
+            let started = Instant::now();
+            match transaction.status().await {
+                Ok(status) if status.ready().is_some() => Some((Ok(status), None)),
+                Ok(status) => {
+                    let delay =
+                        transaction.next_delay(transaction.client.poll_strategy, started.elapsed());
+                    Some((Ok(status), Some((transaction, Some(delay)))))
+                }
+                Err(err) => Some((Err(err), None)),
+            }
+        })
+    }
+
+    /// Stops tracking the transaction without polling it any further.
+    ///
+    /// Duo has no endpoint for cancelling an authentication, so the transaction
+    /// stays pending on Duo's side until it times out on its own. Should Duo
+    /// ever add a cancel endpoint, this is where it will be called.
+    pub fn abandon(self) {
+        self.mark_handled();
+    }
+}
+
+impl Drop for AuthTransaction {
+    /// Warns in debug builds about a transaction that was dropped without
+    /// being polled or abandoned, as the user was notified of an auth that
+    /// nobody looks at.
+    fn drop(&mut self) {
+        #[cfg(all(debug_assertions, feature = "tracing"))]
+        if !self.handled.load(Ordering::Relaxed) {
+            tracing::warn!(
+                txid = %self.txid,
+                "AuthTransaction dropped without being waited on or abandoned"
+            );
+        }
+
+        if let (Some(registry), Some(id)) = (&self.client.registry, self.registry_id) {
+            registry.remove(id);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::jitter;
+    use crate::{random::RandomSource, DuoClient};
+
+    struct Fixed(f64);
+
+    impl RandomSource for Fixed {
+        fn sample(&self, _txid: &str) -> f64 {
+            self.0
+        }
+    }
+
+    fn jittered(sample: f64, fraction: f64, delay: Duration) -> Duration {
+        let client = DuoClient::builder(
+            "https://api-abcdef12.duosecurity.com",
+            "DIWJ8X6AEYOR5OMC6TQ1",
+            "Zh5eGmUq9zpfQnyUIu5OL9iWoMMv5ZNmk3zLJ4Ep",
+        )
+        .poll_jitter(fraction)
+        .random_source(Fixed(sample))
+        .build()
+        .unwrap();
+        jitter(&client.0, delay, "txid")
+    }
+
+    #[test]
+    fn jitter_stays_within_fraction() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(jittered(0.0, 0.2, delay), Duration::from_secs(8));
+        assert_eq!(jittered(0.5, 0.2, delay), delay);
+        assert_eq!(jittered(1.0, 0.2, delay), Duration::from_secs(12));
+    }
+
+    #[test]
+    fn jitter_clamps_out_of_range_input() {
+        let delay = Duration::from_secs(10);
+        assert_eq!(jittered(-3.0, 0.2, delay), Duration::from_secs(8));
+        assert_eq!(jittered(7.0, 0.2, delay), Duration::from_secs(12));
+        // A fraction above one cannot make a delay negative
+        assert_eq!(jittered(0.0, 5.0, delay), Duration::ZERO);
+        assert_eq!(jittered(0.0, 0.0, delay), delay);
+        assert_eq!(jittered(0.0, 0.2, Duration::ZERO), Duration::ZERO);
+    }
+}
@@ -1,19 +1,27 @@
-use serde::Deserialize;
+use std::{cmp::Ordering, collections::BTreeMap, net::IpAddr, time::Duration};
+
+use base64::{
+    alphabet,
+    engine::{DecodePaddingMode, GeneralPurpose, GeneralPurposeConfig},
+    Engine,
+};
+use chrono::{DateTime, TimeZone, Utc};
+use serde::{Deserialize, Serialize};
 use serde_with::serde_as;
 use serde_with::NoneAsEmptyString;
 
-use super::request::Parameters;
+use super::{errors::Error, request::Parameters, transaction::PollStrategy};
 
 structstruck::strike! {
     #[strikethrough[serde_as]]
-    #[strikethrough[derive(Debug, Deserialize)]]
+    #[strikethrough[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]]
     #[serde(rename_all = "snake_case")]
     #[serde(tag = "result")]
     pub enum PreauthResponse {
         Auth {
             devices: Vec<pub struct Device {
                 pub capabilities: Option<Vec<pub enum DeviceCapability {
-                    #![derive(PartialEq, Eq, PartialOrd, Ord)]
+                    #![derive(Copy, PartialOrd, Ord)]
                     #![serde(rename_all = "snake_case")]
 
                     Auto,
@@ -26,11 +34,22 @@ structstruck::strike! {
                 pub display_name: Option<String>,
                 #[serde_as(as = "NoneAsEmptyString")]
                 pub name: Option<String>,
+                /// The phone number, usually masked like `XXX-XXX-1234`. Duo
+                /// sends an empty string for devices without one, e.g.
+                /// tokens, which is read as `None`.
                 #[serde_as(as = "NoneAsEmptyString")]
                 pub number: Option<String>,
                 pub sms_nextcode: Option<String>,
+                /// Passcodes left from the last batch sent to this device. Not
+                /// part of Duo's documented response, so usually absent.
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub remaining_passcodes: Option<u32>,
+                /// Whether the device is locked out. Not part of Duo's
+                /// documented response, so usually absent.
+                #[serde(skip_serializing_if = "Option::is_none")]
+                pub locked_out: Option<bool>,
                 pub r#type: pub enum DeviceType {
-                    #![derive(PartialEq, Eq, PartialOrd, Ord)]
+                    #![derive(Copy, PartialOrd, Ord)]
                     #![serde(rename_all = "snake_case")]
 
                     Phone,
@@ -42,16 +61,353 @@ structstruck::strike! {
         Enroll {
             enroll_portal_url: String,
         },
-        Allow,
+        Allow {
+            status_msg: Option<String>,
+            /// A renewed trusted device token to store in place of the one
+            /// sent with the request. Not part of Duo's documented response,
+            /// so usually absent.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            trusted_device_token: Option<String>,
+            /// When the renewed token stops being accepted, as a Unix
+            /// timestamp. Not part of Duo's documented response either.
+            #[serde(skip_serializing_if = "Option::is_none")]
+            trusted_device_token_expiration: Option<u64>,
+        },
         Deny,
     }
 }
 
+impl PreauthResponse {
+    /// Duo's explanation of an `allow` result, e.g. `"Allowing unknown user"`
+    /// when new users are let through by policy, or a bypass message when the
+    /// user is in bypass status. The exact wording is not documented by Duo.
+    pub fn allow_reason(&self) -> Option<&str> {
+        match self {
+            Self::Allow { status_msg, .. } => status_msg.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// The trusted device token Duo renewed with an `allow` result, if any.
+    /// Duo does not tell whether an `allow` is due to the trusted device
+    /// token or to policy; `allow_reason` is the only hint.
+    pub fn refreshed_trusted_device_token(&self) -> Option<&str> {
+        match self {
+            Self::Allow {
+                trusted_device_token,
+                ..
+            } => trusted_device_token.as_deref(),
+            _ => None,
+        }
+    }
+
+    /// Whether the user has never enrolled a device, as opposed to an
+    /// enrolled user whose devices are all unusable.
+    pub fn needs_enrollment(&self) -> bool {
+        matches!(self, Self::Enroll { .. })
+    }
+
+    /// Whether an `auth` result lists no devices at all. Duo may answer this
+    /// way for an enrolled user whose devices were all removed or are
+    /// unusable, so `devices` must not be assumed to be non-empty.
+    pub fn is_auth_without_devices(&self) -> bool {
+        matches!(self, Self::Auth { devices } if devices.is_empty())
+    }
+
+    /// Whether an `auth` result lists a device that is not locked out and
+    /// advertises at least one capability.
+    pub fn has_usable_device(&self) -> bool {
+        self.devices().iter().any(|device| {
+            device.locked_out != Some(true)
+                && device
+                    .capabilities
+                    .as_ref()
+                    .map_or(false, |capabilities| !capabilities.is_empty())
+        })
+    }
+
+    /// Whether an `auth` result lists devices that are all locked out. This
+    /// relies on the undocumented `locked_out` field, and is `false` when Duo
+    /// does not send it.
+    pub fn all_devices_locked(&self) -> bool {
+        let devices = self.devices();
+        !devices.is_empty() && devices.iter().all(|device| device.locked_out == Some(true))
+    }
+
+    /// Whether the user is locked out, as far as preauth tells. Duo answers
+    /// preauth for a locked out user with a plain `deny`, which this cannot
+    /// tell apart from other denials, so only an `auth` result whose devices
+    /// are all locked out counts, see [`PreauthResponse::all_devices_locked`].
+    pub fn is_locked_out(&self) -> bool {
+        self.all_devices_locked()
+    }
+
+    /// Picks the factor for the first device, in order of `preference`, that
+    /// has a preferred capability, e.g. to prefer push over phone where Duo's
+    /// `auto` factor would decide on its own. `mobile_otp` is skipped, as it
+    /// needs a passcode from the user. `None` if no device is capable.
+    pub fn best_factor(&self, preference: &[DeviceCapability]) -> Option<AuthRequestFactor> {
+        let preference: Vec<_> = preference
+            .iter()
+            .copied()
+            .filter(|capability| *capability != DeviceCapability::MobileOtp)
+            .collect();
+        let (device, capability) = select_device(self.devices(), &preference)?;
+        let device = device.device.clone();
+
+        Some(match capability {
+            DeviceCapability::Auto => AuthRequestFactor::Auto {
+                device: Some(device),
+                r#type: None,
+                display_username: None,
+                push_info: None,
+            },
+            DeviceCapability::Push => AuthRequestFactor::Push {
+                device,
+                r#type: None,
+                display_username: None,
+                push_info: None,
+            },
+            DeviceCapability::Phone => AuthRequestFactor::Phone {
+                device: DeviceSelector::Id(device),
+            },
+            DeviceCapability::Sms => AuthRequestFactor::Sms {
+                device: DeviceSelector::Id(device),
+            },
+            DeviceCapability::MobileOtp => return None,
+        })
+    }
+
+    fn devices(&self) -> &[Device] {
+        match self {
+            Self::Auth { devices } => devices,
+            _ => &[],
+        }
+    }
+}
+
+/// The ranking used by [`Device::best_capability`], most preferred first.
+pub const DEFAULT_CAPABILITY_RANKING: [DeviceCapability; 5] = [
+    DeviceCapability::Push,
+    DeviceCapability::MobileOtp,
+    DeviceCapability::Phone,
+    DeviceCapability::Sms,
+    DeviceCapability::Auto,
+];
+
+impl Device {
+    /// The device's most preferred capability by
+    /// [`DEFAULT_CAPABILITY_RANKING`], e.g. to recommend a factor per device.
+    pub fn best_capability(&self) -> Option<DeviceCapability> {
+        let rank = |capability: &DeviceCapability| {
+            DEFAULT_CAPABILITY_RANKING
+                .iter()
+                .position(|ranked| ranked == capability)
+        };
+
+        self.best_capability_by(|a, b| rank(b).cmp(&rank(a)))
+    }
+
+    /// Like [`Device::best_capability`], with a custom ranking: the
+    /// capability that `compare` orders greatest is the best.
+    pub fn best_capability_by<F>(&self, mut compare: F) -> Option<DeviceCapability>
+    where
+        F: FnMut(&DeviceCapability, &DeviceCapability) -> Ordering,
+    {
+        self.capabilities
+            .as_ref()?
+            .iter()
+            .copied()
+            .max_by(|a, b| compare(a, b))
+    }
+
+    /// A name to show for the device: the first non-blank of `display_name`,
+    /// `name` and the phone number, masked or not, falling back to the device
+    /// id.
+    pub fn label(&self) -> String {
+        [
+            self.display_name.as_deref(),
+            self.name.as_deref(),
+            self.number.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .map(str::trim)
+        .find(|label| !label.is_empty())
+        .unwrap_or(&self.device)
+        .to_string()
+    }
+
+    /// The phone number for display, only if it is masked, so an unmasked
+    /// number is never shown by accident.
+    pub fn masked_number(&self) -> Option<&str> {
+        self.number.as_deref().filter(|_| self.is_number_masked())
+    }
+
+    /// Whether the phone number hides digits behind `X` or `*`, as Duo does
+    /// for all but the last few digits.
+    pub fn is_number_masked(&self) -> bool {
+        self.number
+            .as_deref()
+            .map_or(false, |number| number.contains(['X', 'x', '*']))
+    }
+
+    pub fn has_capability(&self, capability: DeviceCapability) -> bool {
+        self.capabilities
+            .as_ref()
+            .map(|capabilities| capabilities.contains(&capability))
+            .unwrap_or(false)
+    }
+
+    pub fn push_request(&self, user: &User) -> AuthRequest {
+        AuthRequest::new(
+            user.clone(),
+            AuthRequestFactor::Push {
+                device: self.device.clone(),
+                r#type: None,
+                display_username: None,
+                push_info: None,
+            },
+        )
+    }
+
+    pub fn phone_request(&self, user: &User) -> AuthRequest {
+        AuthRequest::new(
+            user.clone(),
+            AuthRequestFactor::Phone {
+                device: DeviceSelector::Id(self.device.clone()),
+            },
+        )
+    }
+
+    pub fn sms_request(&self, user: &User) -> AuthRequest {
+        AuthRequest::new(
+            user.clone(),
+            AuthRequestFactor::Sms {
+                device: DeviceSelector::Id(self.device.clone()),
+            },
+        )
+    }
+
+    /// Builds a passcode auth for a passcode generated by Duo Mobile on this
+    /// device, or `None` when the device does not advertise `mobile_otp`.
+    pub fn otp_auth_request(&self, user: &User, passcode: String) -> Option<AuthRequest> {
+        if !self.has_capability(DeviceCapability::MobileOtp) {
+            return None;
+        }
+
+        Some(AuthRequest::new(
+            user.clone(),
+            AuthRequestFactor::Passcode { passcode },
+        ))
+    }
+}
+
+/// Picks the first device, in order of `preference`, that has one of the
+/// preferred capabilities.
+pub(crate) fn select_device<'a>(
+    devices: &'a [Device],
+    preference: &[DeviceCapability],
+) -> Option<(&'a Device, DeviceCapability)> {
+    preference.iter().find_map(|capability| {
+        devices
+            .iter()
+            .find(|device| device.has_capability(*capability))
+            .map(|device| (device, *capability))
+    })
+}
+
+#[derive(Clone, Debug)]
+pub struct AuthenticateOptions {
+    /// Capabilities to authenticate with, most preferred first. Only
+    /// capabilities that do not need user input are used: `push`, `phone`
+    /// and `auto`.
+    pub preference: Vec<DeviceCapability>,
+    /// Overrides the client's poll strategy while waiting for the result
+    pub poll_strategy: Option<PollStrategy>,
+}
+
+impl Default for AuthenticateOptions {
+    fn default() -> Self {
+        Self {
+            preference: vec![DeviceCapability::Push, DeviceCapability::Phone],
+            poll_strategy: None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum AuthOutcome {
+    /// Preauth allowed the user without a second factor
+    Bypassed {
+        status_msg: Option<String>,
+    },
+    Allowed(AuthStatusResponse),
+    /// Denied by preauth, or by the second factor if a status is present
+    Denied(Option<AuthStatusResponse>),
+    NeedsEnrollment {
+        enroll_portal_url: String,
+    },
+    /// None of the user's devices has a preferred capability
+    NoCapableDevice,
+    /// The user is locked out after too many failed attempts, and needs to
+    /// be unlocked by an administrator. Holds the auth status if the lockout
+    /// was reported by the second factor rather than found by preauth.
+    LockedOut(Option<AuthStatusResponse>),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Failmode {
+    /// Treat an unavailable Duo as if preauth returned `allow`.
+    Open,
+    /// Propagate the error when Duo is unavailable.
+    Closed,
+}
+
+/// Result of [`DuoClient::health`](crate::DuoClient::health).
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct HealthStatus {
+    /// Whether `ping`, which needs no credentials, got an answer
+    pub reachable: bool,
+    /// Whether `check` accepted the credentials, `None` if Duo could not be
+    /// reached to tell
+    pub credentials_valid: Option<bool>,
+}
+
+impl HealthStatus {
+    pub fn is_healthy(&self) -> bool {
+        self.reachable && self.credentials_valid == Some(true)
+    }
+}
+
+/// Result of [`DuoClient::preauth_or_enroll`](crate::DuoClient::preauth_or_enroll).
+#[derive(Debug)]
+pub enum PreauthOrEnroll {
+    /// Any preauth result other than `enroll`
+    Preauth(PreauthResponse),
+    NeedsEnrollment {
+        enroll_portal_url: String,
+        /// A fresh activation code, if enrolling was requested
+        activation: Option<EnrollResponse>,
+    },
+}
+
+/// Can be stored and compared, e.g. to skip preauth for a user with a recent
+/// outcome cached by the application.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct PreauthOutcome {
+    pub response: PreauthResponse,
+    /// Set when `response` was not returned by Duo, but synthesized because
+    /// Duo was unavailable under [`Failmode::Open`].
+    pub failmode_allow: bool,
+}
+
 structstruck::strike! {
     #[strikethrough[serde_as]]
-    #[strikethrough[derive(Deserialize, Debug)]]
+    #[strikethrough[derive(Clone, Deserialize, Serialize, Debug)]]
     pub struct AuthStatusResponse {
         pub result: pub enum AuthResult {
+            #![derive(Copy, PartialEq, Eq)]
             #![serde(rename_all = "snake_case")]
 
             Allow,
@@ -59,6 +415,7 @@ structstruck::strike! {
             Waiting,
         },
         pub status: pub enum AuthStatus {
+            #![derive(Copy, PartialEq, Eq)]
             #![serde(rename_all = "snake_case")]
 
             Calling,
@@ -78,6 +435,50 @@ structstruck::strike! {
     }
 }
 
+/// What Duo answered to an auth: a txid to poll, or the final result if Duo
+/// decided right away, e.g. for a bypassed user or a trusted device, which
+/// can happen even for async auths.
+#[derive(Clone, Debug, Deserialize)]
+#[serde(from = "AuthStartResponse")]
+pub enum AuthStart {
+    Pending(String),
+    Immediate(AuthStatusResponse),
+}
+
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum AuthStartResponse {
+    Pending { txid: String },
+    Immediate(AuthStatusResponse),
+}
+
+impl From<AuthStartResponse> for AuthStart {
+    fn from(response: AuthStartResponse) -> Self {
+        match response {
+            AuthStartResponse::Pending { txid } => Self::Pending(txid),
+            AuthStartResponse::Immediate(status) => Self::Immediate(status),
+        }
+    }
+}
+
+impl AuthStart {
+    pub fn txid(&self) -> Option<&str> {
+        match self {
+            Self::Pending(txid) => Some(txid),
+            Self::Immediate(_) => None,
+        }
+    }
+
+    /// Returns the txid, or [`Error::AuthCompleted`] with the result if
+    /// there is nothing to poll.
+    pub fn into_txid(self) -> Result<String, Error> {
+        match self {
+            Self::Pending(txid) => Ok(txid),
+            Self::Immediate(status) => Err(Error::AuthCompleted(Box::new(status))),
+        }
+    }
+}
+
 impl AuthStatusResponse {
     pub fn ready(&self) -> Option<bool> {
         match self.result {
@@ -86,6 +487,147 @@ impl AuthStatusResponse {
             AuthResult::Waiting => None,
         }
     }
+
+    /// Whether the user picked up the phone call and Duo is waiting for them
+    /// to confirm or deny with a key press.
+    pub fn call_answered(&self) -> bool {
+        self.status == AuthStatus::Answered
+    }
+
+    /// Whether Duo denied the auth because the user is locked out, which it
+    /// reports with status `locked_out`.
+    pub fn is_locked_out(&self) -> bool {
+        self.result == AuthResult::Deny && self.status == AuthStatus::LockedOut
+    }
+
+    /// Combines `result` and `status` into a single state.
+    ///
+    /// `result` always takes precedence: an `allow` result is `Approved` and a
+    /// `deny` result is `Denied` or `Failed` regardless of what `status` says.
+    /// `status` is only used to refine the state, and to tell how far along a
+    /// waiting transaction is.
+    pub fn progress(&self) -> AuthProgress {
+        match self.result {
+            AuthResult::Allow => AuthProgress::Approved,
+            AuthResult::Deny => match self.status {
+                AuthStatus::PushFailed => AuthProgress::Failed,
+                AuthStatus::Timeout => AuthProgress::Denied(DenyReason::Timeout),
+                AuthStatus::Fraud => AuthProgress::Denied(DenyReason::Fraud),
+                AuthStatus::LockedOut => AuthProgress::Denied(DenyReason::LockedOut),
+                AuthStatus::Sent => AuthProgress::Denied(DenyReason::PasscodesSent),
+                AuthStatus::Calling
+                | AuthStatus::Answered
+                | AuthStatus::Pushed
+                | AuthStatus::Allow
+                | AuthStatus::Bypass
+                | AuthStatus::Deny => AuthProgress::Denied(DenyReason::Denied),
+            },
+            AuthResult::Waiting => match self.status {
+                AuthStatus::Calling => AuthProgress::Queued,
+                AuthStatus::Answered => AuthProgress::Answered,
+                AuthStatus::Pushed
+                | AuthStatus::Sent
+                | AuthStatus::PushFailed
+                | AuthStatus::Timeout
+                | AuthStatus::Fraud
+                | AuthStatus::Allow
+                | AuthStatus::Bypass
+                | AuthStatus::Deny
+                | AuthStatus::LockedOut => AuthProgress::Delivered,
+            },
+        }
+    }
+
+    /// Turns an approval into `Ok`, and anything else into
+    /// [`Error::AuthDenied`], or [`Error::StillWaiting`] while the user has
+    /// not answered yet, following [`AuthStatusResponse::progress`].
+    pub fn into_result(self) -> Result<AuthAllowed, Error> {
+        let reason = match self.progress() {
+            AuthProgress::Approved => {
+                return Ok(AuthAllowed {
+                    trusted_device_token: self.trusted_device_token,
+                })
+            }
+            AuthProgress::Denied(reason) => Some(reason),
+            AuthProgress::Failed => None,
+            AuthProgress::Queued | AuthProgress::Delivered | AuthProgress::Answered => {
+                return Err(Error::StillWaiting)
+            }
+        };
+
+        Err(Error::AuthDenied {
+            reason,
+            status_msg: self.status_msg,
+        })
+    }
+}
+
+/// An approved auth, see [`AuthStatusResponse::into_result`].
+#[derive(Clone, Debug)]
+pub struct AuthAllowed {
+    pub trusted_device_token: Option<String>,
+}
+
+/// Result of sending a batch of SMS passcodes, see
+/// [`DuoClient::send_sms_passcodes`](crate::DuoClient::send_sms_passcodes).
+#[derive(Clone, Debug)]
+pub struct SmsSendResult {
+    /// The number of passcodes sent, if Duo mentions it in `message`. Duo's
+    /// usual message, "New SMS passcodes sent", does not include a count.
+    pub codes_sent: Option<u32>,
+    pub message: String,
+}
+
+impl SmsSendResult {
+    pub(crate) fn from_status_msg(message: String) -> Self {
+        let codes_sent = message
+            .split(|c: char| !c.is_ascii_digit())
+            .find(|digits| !digits.is_empty())
+            .and_then(|digits| digits.parse().ok());
+
+        Self {
+            codes_sent,
+            message,
+        }
+    }
+}
+
+impl AuthStatus {
+    pub fn is_terminal(&self) -> bool {
+        match self {
+            Self::Calling | Self::Answered | Self::Pushed | Self::Sent => false,
+            Self::PushFailed
+            | Self::Timeout
+            | Self::Fraud
+            | Self::Allow
+            | Self::Bypass
+            | Self::Deny
+            | Self::LockedOut => true,
+        }
+    }
+
+    pub fn is_in_progress(&self) -> bool {
+        !self.is_terminal()
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AuthProgress {
+    Queued,
+    Delivered,
+    Answered,
+    Approved,
+    Denied(DenyReason),
+    Failed,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenyReason {
+    Denied,
+    Timeout,
+    Fraud,
+    LockedOut,
+    PasscodesSent,
 }
 
 #[derive(Clone, Debug)]
@@ -94,12 +636,40 @@ pub enum User {
     Username { username: String },
 }
 
+pub type UsernameNormalizer = Box<dyn Fn(&str) -> String + Send + Sync>;
+pub type DisplayUsername = Box<dyn Fn(&User) -> Option<String> + Send + Sync>;
+
+/// Client-wide settings that affect how requests are turned into parameters.
+#[derive(Default)]
+pub(crate) struct ApplyOptions {
+    pub(crate) normalize_username: Option<UsernameNormalizer>,
+    pub(crate) display_username: Option<DisplayUsername>,
+}
+
 impl User {
-    pub(crate) fn apply(self, parameters: &mut Parameters) {
+    pub(crate) fn apply(self, parameters: &mut Parameters, options: &ApplyOptions) {
         match self {
             Self::UserId { id } => parameters.set("user_id", id),
-            Self::Username { username } => parameters.set("username", username),
+            Self::Username { username } => match &options.normalize_username {
+                Some(normalize) => parameters.set("username", normalize(&username)),
+                None => parameters.set("username", username),
+            },
+        };
+    }
+
+    pub(crate) fn validate(&self) -> Result<(), Error> {
+        let (name, value) = match self {
+            Self::UserId { id } => ("user_id", id),
+            Self::Username { username } => ("username", username),
         };
+
+        if value.is_empty() {
+            return Err(Error::InvalidParameter {
+                name,
+                reason: "empty".into(),
+            });
+        }
+        Ok(())
     }
 
     pub fn user_id<S: Into<String>>(id: S) -> Self {
@@ -113,6 +683,61 @@ impl User {
     }
 }
 
+/// Turns what may be a URL or `host:port` into a lowercase ASCII host name,
+/// as Duo applies policy by host name. Anything that is not plausibly a host
+/// name or IP address is rejected.
+pub(crate) fn normalize_hostname(hostname: &str) -> Result<String, Error> {
+    let invalid = |reason: &str| Error::InvalidParameter {
+        name: "hostname",
+        reason: reason.into(),
+    };
+
+    let mut host = hostname.trim();
+    if let Some((_, rest)) = host.split_once("://") {
+        host = rest;
+    }
+    host = host.split(['/', '?', '#']).next().unwrap_or_default();
+    if let Some((_, rest)) = host.rsplit_once('@') {
+        host = rest;
+    }
+
+    if let Some(rest) = host.strip_prefix('[') {
+        // Bracketed IPv6 address, possibly with a port
+        host = rest
+            .split_once(']')
+            .ok_or_else(|| invalid("unterminated IPv6 address"))?
+            .0;
+    } else if let Some((name, port)) = host.split_once(':') {
+        // More than one colon is a bare IPv6 address
+        if !port.contains(':') {
+            if !port.chars().all(|c| c.is_ascii_digit()) {
+                return Err(invalid("invalid port"));
+            }
+            host = name;
+        }
+    }
+
+    let mut host = host.trim_end_matches('.').to_ascii_lowercase();
+    if !host.is_ascii() {
+        // Internationalized names are sent in their punycode form
+        host = match url::Host::parse(&host) {
+            Ok(url::Host::Domain(domain)) => domain,
+            _ => return Err(invalid("invalid internationalized name")),
+        };
+    }
+    if host.is_empty() || host.len() > 253 {
+        return Err(invalid("empty or too long"));
+    }
+    if !host
+        .chars()
+        .all(|c| c.is_ascii_alphanumeric() || matches!(c, '-' | '.' | '_' | ':'))
+    {
+        return Err(invalid("unexpected characters"));
+    }
+
+    Ok(host)
+}
+
 #[derive(Clone, Debug)]
 pub struct PreauthRequest {
     pub user: User,
@@ -131,11 +756,32 @@ impl PreauthRequest {
         }
     }
 
-    pub(crate) fn apply(self, parameters: &mut Parameters) {
-        self.user.apply(parameters);
+    /// The parameters this request is sent with, with secrets redacted.
+    /// Hooks set on the client are not applied, see
+    /// [`DuoClient::preauth_parameters`](crate::DuoClient::preauth_parameters).
+    pub fn parameters(&self) -> Result<BTreeMap<String, String>, Error> {
+        let mut parameters = Parameters::default();
+        self.clone()
+            .apply(&mut parameters, &ApplyOptions::default())?;
+        Ok(parameters.redacted())
+    }
+
+    pub(crate) fn apply(
+        self,
+        parameters: &mut Parameters,
+        options: &ApplyOptions,
+    ) -> Result<(), Error> {
+        self.user.apply(parameters, options);
         parameters.set_opt("ipaddr", self.ipaddr);
-        parameters.set_opt("hostname", self.hostname);
+        parameters.set_opt(
+            "hostname",
+            self.hostname
+                .as_deref()
+                .map(normalize_hostname)
+                .transpose()?,
+        );
         parameters.set_opt("trusted_device_token", self.trusted_device_token);
+        Ok(())
     }
 }
 
@@ -156,9 +802,12 @@ structstruck::strike! {
                 display_username: Option<String>,
                 push_info: Option<String>,
             },
+            /// Sent exactly as given. Take passcodes from user input as
+            /// strings: going through an integer drops leading zeros, and
+            /// Duo denies the truncated passcode. See [`Passcode`].
             Passcode { passcode: String },
-            Phone { device: String },
-            Sms { device: String },
+            Phone { device: DeviceSelector },
+            Sms { device: DeviceSelector },
         },
         pub ipaddr: Option<String>,
         pub hostname: Option<String>,
@@ -175,11 +824,204 @@ impl AuthRequest {
         }
     }
 
-    pub(crate) fn apply(self, parameters: &mut Parameters) {
-        self.user.apply(parameters);
-        self.factor.apply(parameters);
+    /// Builds an auth for `device` with `factor`, failing with
+    /// [`Error::UnsupportedFactor`] if the device does not advertise the
+    /// matching capability.
+    pub fn for_device(user: User, device: &Device, factor: PreferredFactor) -> Result<Self, Error> {
+        let capability = factor.capability();
+        if !device.has_capability(capability) {
+            return Err(Error::UnsupportedFactor {
+                device: device.device.clone(),
+                capability,
+            });
+        }
+
+        Ok(match factor {
+            PreferredFactor::Push => device.push_request(&user),
+            PreferredFactor::Phone => device.phone_request(&user),
+            PreferredFactor::Sms => device.sms_request(&user),
+            PreferredFactor::Passcode(passcode) => {
+                AuthRequest::new(user, AuthRequestFactor::Passcode { passcode })
+            }
+        })
+    }
+
+    /// Checks locally for parameters Duo would reject: empty user, device or
+    /// passcode, or a malformed IP address or hostname. Passcodes are not
+    /// required to be numeric, as hardware tokens such as YubiKeys send
+    /// modhex OTPs.
+    pub fn validate(&self) -> Result<(), Error> {
+        let invalid = |name, reason: &str| {
+            Err(Error::InvalidParameter {
+                name,
+                reason: reason.into(),
+            })
+        };
+
+        self.user.validate()?;
+
+        match &self.factor {
+            AuthRequestFactor::Auto { device, .. } => {
+                if device.as_deref() == Some("") {
+                    return invalid("device", "empty");
+                }
+            }
+            AuthRequestFactor::Push { device, .. } => {
+                if device.is_empty() {
+                    return invalid("device", "empty");
+                }
+            }
+            AuthRequestFactor::Phone { device } | AuthRequestFactor::Sms { device } => {
+                if device.as_str().is_empty() {
+                    return invalid("device", "empty");
+                }
+            }
+            AuthRequestFactor::Passcode { passcode } => {
+                if passcode.is_empty() {
+                    return invalid("passcode", "empty");
+                }
+            }
+        }
+
+        if let Some(ipaddr) = &self.ipaddr {
+            if ipaddr.parse::<IpAddr>().is_err() {
+                return invalid("ipaddr", "not an IP address");
+            }
+        }
+
+        if let Some(hostname) = &self.hostname {
+            normalize_hostname(hostname)?;
+        }
+
+        Ok(())
+    }
+
+    /// The parameters this request is sent with, with secrets redacted. The
+    /// `async` parameter and hooks set on the client, such as a username
+    /// normalizer, are not applied, see
+    /// [`DuoClient::auth_parameters`](crate::DuoClient::auth_parameters).
+    pub fn parameters(&self) -> Result<BTreeMap<String, String>, Error> {
+        let mut parameters = Parameters::default();
+        self.clone()
+            .apply(&mut parameters, &ApplyOptions::default())?;
+        Ok(parameters.redacted())
+    }
+
+    pub(crate) fn apply(
+        self,
+        parameters: &mut Parameters,
+        options: &ApplyOptions,
+    ) -> Result<(), Error> {
+        self.factor.apply(parameters, options, &self.user);
+        self.user.apply(parameters, options);
         parameters.set_opt("ipaddr", self.ipaddr);
-        parameters.set_opt("hostname", self.hostname);
+        parameters.set_opt(
+            "hostname",
+            self.hostname
+                .as_deref()
+                .map(normalize_hostname)
+                .transpose()?,
+        );
+        Ok(())
+    }
+}
+
+/// The device a phone call or SMS goes to.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// The user's default phone, sent as `auto`
+    Auto,
+    Id(String),
+}
+
+impl DeviceSelector {
+    pub fn as_str(&self) -> &str {
+        match self {
+            Self::Auto => "auto",
+            Self::Id(id) => id,
+        }
+    }
+}
+
+impl From<String> for DeviceSelector {
+    fn from(id: String) -> Self {
+        Self::Id(id)
+    }
+}
+
+impl From<&str> for DeviceSelector {
+    fn from(id: &str) -> Self {
+        Self::Id(id.into())
+    }
+}
+
+impl From<DeviceSelector> for String {
+    fn from(device: DeviceSelector) -> Self {
+        match device {
+            DeviceSelector::Auto => "auto".into(),
+            DeviceSelector::Id(id) => id,
+        }
+    }
+}
+
+/// A passcode as the user entered it. There is deliberately no conversion
+/// from integers, which would lose leading zeros.
+#[derive(Clone, PartialEq, Eq)]
+pub struct Passcode(String);
+
+impl Passcode {
+    /// Accepts a non-empty string of ASCII digits, keeping it exactly as is,
+    /// leading zeros included.
+    pub fn from_digits(digits: &str) -> Result<Self, Error> {
+        let invalid = |reason: &str| Error::InvalidParameter {
+            name: "passcode",
+            reason: reason.into(),
+        };
+
+        if digits.is_empty() {
+            return Err(invalid("empty"));
+        }
+        if !digits.chars().all(|c| c.is_ascii_digit()) {
+            return Err(invalid("not numeric"));
+        }
+        Ok(Self(digits.to_string()))
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl std::fmt::Debug for Passcode {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("Passcode(<redacted>)")
+    }
+}
+
+impl From<Passcode> for String {
+    fn from(passcode: Passcode) -> Self {
+        passcode.0
+    }
+}
+
+/// A second factor on a specific device, see [`AuthRequest::for_device`].
+#[derive(Clone, Debug)]
+pub enum PreferredFactor {
+    Push,
+    Phone,
+    Sms,
+    /// A passcode generated by Duo Mobile on the device
+    Passcode(String),
+}
+
+impl PreferredFactor {
+    pub fn capability(&self) -> DeviceCapability {
+        match self {
+            Self::Push => DeviceCapability::Push,
+            Self::Phone => DeviceCapability::Phone,
+            Self::Sms => DeviceCapability::Sms,
+            Self::Passcode(_) => DeviceCapability::MobileOtp,
+        }
     }
 }
 
@@ -193,7 +1035,17 @@ impl AuthRequestFactor {
         }
     }
 
-    pub(crate) fn apply(self, parameters: &mut Parameters) {
+    pub fn passcode(passcode: Passcode) -> Self {
+        Self::Passcode {
+            passcode: passcode.into(),
+        }
+    }
+
+    pub(crate) fn apply(self, parameters: &mut Parameters, options: &ApplyOptions, user: &User) {
+        let or_default_display_username = |display_username: Option<String>| {
+            display_username.or_else(|| options.display_username.as_ref()?(user))
+        };
+
         match self {
             Self::Auto {
                 device,
@@ -204,7 +1056,10 @@ impl AuthRequestFactor {
                 parameters.set("factor", "auto");
                 parameters.set_opt("device", device);
                 parameters.set_opt("type", r#type);
-                parameters.set_opt("display_username", display_username);
+                parameters.set_opt(
+                    "display_username",
+                    or_default_display_username(display_username),
+                );
                 parameters.set_opt("push_info", push_info);
             }
             Self::Push {
@@ -216,7 +1071,10 @@ impl AuthRequestFactor {
                 parameters.set("factor", "push");
                 parameters.set("device", device);
                 parameters.set_opt("type", r#type);
-                parameters.set_opt("display_username", display_username);
+                parameters.set_opt(
+                    "display_username",
+                    or_default_display_username(display_username),
+                );
                 parameters.set_opt("push_info", push_info);
             }
             Self::Passcode { passcode } => {
@@ -235,7 +1093,7 @@ impl AuthRequestFactor {
     }
 }
 
-#[derive(Debug, Deserialize)]
+#[derive(Clone, Debug, Deserialize, Serialize)]
 pub struct EnrollResponse {
     pub activation_barcode: String,
     pub activation_code: String,
@@ -244,10 +1102,157 @@ pub struct EnrollResponse {
     pub username: String,
 }
 
-#[derive(Debug, Deserialize)]
+impl EnrollResponse {
+    /// When the activation code stops working, from `expiration`.
+    pub fn expires_at(&self) -> Option<DateTime<Utc>> {
+        Utc.timestamp_opt(i64::try_from(self.expiration).ok()?, 0)
+            .single()
+    }
+
+    /// How long until the activation code expires by the local clock, e.g.
+    /// for a countdown, or `None` once it has expired.
+    pub fn time_remaining(&self) -> Option<Duration> {
+        self.expires_at()?
+            .signed_duration_since(Utc::now())
+            .to_std()
+            .ok()
+            .filter(|remaining| !remaining.is_zero())
+    }
+
+    /// Splits `activation_code`, which looks like `duo://<code>-<host>` with
+    /// the API host base64-encoded, into its components. Returns `None` if
+    /// the code does not have that shape.
+    pub fn parsed_activation(&self) -> Option<ActivationCode> {
+        let rest = self.activation_code.strip_prefix("duo://")?;
+        let (code, host) = rest.rsplit_once('-')?;
+        if code.is_empty() {
+            return None;
+        }
+
+        let engine = GeneralPurpose::new(
+            &alphabet::STANDARD,
+            GeneralPurposeConfig::new().with_decode_padding_mode(DecodePaddingMode::Indifferent),
+        );
+        let api_host = String::from_utf8(engine.decode(host).ok()?).ok()?;
+
+        Some(ActivationCode {
+            code: code.to_string(),
+            api_host,
+        })
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ActivationCode {
+    pub code: String,
+    pub api_host: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
 pub enum EnrollStatusResponse {
     Success,
     Invalid,
     Waiting,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn status(result: AuthResult, status: AuthStatus) -> AuthStatusResponse {
+        AuthStatusResponse {
+            result,
+            status,
+            status_msg: String::new(),
+            trusted_device_token: None,
+        }
+    }
+
+    #[test]
+    fn progress_follows_result_first() {
+        use AuthProgress::*;
+
+        let cases = [
+            (AuthResult::Waiting, AuthStatus::Calling, Queued),
+            (AuthResult::Waiting, AuthStatus::Pushed, Delivered),
+            (AuthResult::Waiting, AuthStatus::Sent, Delivered),
+            (AuthResult::Waiting, AuthStatus::Answered, Answered),
+            (AuthResult::Allow, AuthStatus::Allow, Approved),
+            (AuthResult::Allow, AuthStatus::Bypass, Approved),
+            // `result` wins over a contradicting `status`
+            (AuthResult::Allow, AuthStatus::Deny, Approved),
+            (
+                AuthResult::Deny,
+                AuthStatus::Allow,
+                Denied(DenyReason::Denied),
+            ),
+            (
+                AuthResult::Deny,
+                AuthStatus::Deny,
+                Denied(DenyReason::Denied),
+            ),
+            (
+                AuthResult::Deny,
+                AuthStatus::Timeout,
+                Denied(DenyReason::Timeout),
+            ),
+            (
+                AuthResult::Deny,
+                AuthStatus::Fraud,
+                Denied(DenyReason::Fraud),
+            ),
+            (
+                AuthResult::Deny,
+                AuthStatus::LockedOut,
+                Denied(DenyReason::LockedOut),
+            ),
+            (
+                AuthResult::Deny,
+                AuthStatus::Sent,
+                Denied(DenyReason::PasscodesSent),
+            ),
+            (AuthResult::Deny, AuthStatus::PushFailed, Failed),
+        ];
+        for (result, auth_status, progress) in cases {
+            assert_eq!(
+                status(result, auth_status).progress(),
+                progress,
+                "{result:?}/{auth_status:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn terminal_statuses() {
+        use AuthStatus::*;
+
+        for auth_status in [Calling, Answered, Pushed, Sent] {
+            assert!(!auth_status.is_terminal(), "{auth_status:?}");
+            assert!(auth_status.is_in_progress(), "{auth_status:?}");
+        }
+        for auth_status in [PushFailed, Timeout, Fraud, Allow, Bypass, Deny, LockedOut] {
+            assert!(auth_status.is_terminal(), "{auth_status:?}");
+            assert!(!auth_status.is_in_progress(), "{auth_status:?}");
+        }
+    }
+
+    #[test]
+    fn hostnames_are_normalized() {
+        let normalized = |hostname| normalize_hostname(hostname).unwrap();
+
+        assert_eq!(normalized("VPN.Example.COM"), "vpn.example.com");
+        assert_eq!(normalized("vpn.example.com."), "vpn.example.com");
+        assert_eq!(
+            normalized("https://vpn.example.com:8443/login"),
+            "vpn.example.com"
+        );
+        assert_eq!(normalized("Bücher.Example"), "xn--bcher-kva.example");
+        assert_eq!(normalized("[2001:db8::1]:443"), "2001:db8::1");
+        assert_eq!(normalized("intranet_01"), "intranet_01");
+
+        assert!(normalize_hostname("").is_err());
+        assert!(normalize_hostname("vpn.example.com:https").is_err());
+        assert!(normalize_hostname("vpn example.com").is_err());
+    }
+}
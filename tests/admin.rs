@@ -0,0 +1,85 @@
+#![cfg(feature = "admin")]
+
+mod common;
+
+use duo_auth::{errors::Error, DuoClient};
+use futures_util::TryStreamExt;
+use reqwest::{Request, Response};
+
+/// Serves five users in pages of at most two.
+fn users(request: Request) -> Result<Response, reqwest::Error> {
+    let offset: u64 = request
+        .url()
+        .query_pairs()
+        .find(|(name, _)| name == "offset")
+        .unwrap()
+        .1
+        .parse()
+        .unwrap();
+
+    let users: Vec<_> = (offset..5.min(offset + 2))
+        .map(|id| format!(r#"{{"user_id": "DU{id}", "username": "user{id}"}}"#))
+        .collect();
+    let next_offset = if offset + 2 < 5 {
+        (offset + 2).to_string()
+    } else {
+        "null".to_string()
+    };
+    let body = format!(
+        r#"{{"stat": "OK", "response": [{}], "metadata": {{"next_offset": {next_offset}, "total_objects": 5}}}}"#,
+        users.join(", ")
+    );
+
+    Ok(http::Response::builder()
+        .status(200)
+        .body(body)
+        .unwrap()
+        .into())
+}
+
+#[tokio::test]
+async fn user_pages_follow_next_offset() {
+    let client = common::client(users);
+
+    let pages: Vec<_> = client.user_pages(2).try_collect().await.unwrap();
+    let usernames: Vec<_> = pages
+        .iter()
+        .flat_map(|page| &page.items)
+        .map(|user| user.username.as_str())
+        .collect();
+    assert_eq!(pages.len(), 3);
+    assert_eq!(usernames, ["user0", "user1", "user2", "user3", "user4"]);
+}
+
+fn resync_client(body: &'static str) -> DuoClient {
+    common::client(
+        move |request: Request| -> Result<Response, reqwest::Error> {
+            assert_eq!(
+                request.url().path(),
+                "/admin/v1/tokens/DHIZ34ALBA2445ND4AI2/resync"
+            );
+            common::respond(400, body)(request)
+        },
+    )
+}
+
+#[tokio::test]
+async fn rejected_resync_codes() {
+    let codes = || ["123456", "234567", "345678"].map(String::from);
+
+    let err = resync_client(r#"{"stat": "FAIL", "code": 40002, "message": "Resync failed"}"#)
+        .resync_token("DHIZ34ALBA2445ND4AI2", codes())
+        .await
+        .unwrap_err();
+    assert!(
+        matches!(err, Error::TokenOutOfSync { token_id } if token_id == "DHIZ34ALBA2445ND4AI2")
+    );
+
+    let err = resync_client(
+        r#"{"stat": "FAIL", "code": 40002, "message": "Invalid request parameters", "message_detail": "code1"}"#,
+    )
+    .resync_token("DHIZ34ALBA2445ND4AI2", codes())
+    .await
+    .unwrap_err();
+    assert!(matches!(err, Error::ApiRequestFailed { code: 40002, .. }));
+}
@@ -0,0 +1,231 @@
+mod common;
+
+use chrono::DateTime;
+use duo_auth::{
+    errors::Error,
+    request::RequestContext,
+    signing,
+    types::{
+        AuthRequest, AuthRequestFactor, AuthResult, AuthStart, AuthStatusResponse, DenyReason,
+        Device, PreauthRequest, User,
+    },
+    DuoClient,
+};
+use reqwest::{Request, Response};
+
+const PENDING: &str =
+    r#"{"stat": "OK", "response": {"txid": "45f7c92b-f45f-4862-8545-e0f58e78075a"}}"#;
+const IMMEDIATE: &str = r#"{"stat": "OK", "response": {"result": "allow", "status": "bypass", "status_msg": "Bypassing user", "trusted_device_token": null}}"#;
+
+fn client(auth_body: &'static str) -> DuoClient {
+    common::client(
+        move |request: Request| -> Result<Response, reqwest::Error> {
+            let body = match request.url().path() {
+                "/auth/v2/auth" => auth_body,
+                _ => {
+                    r#"{"stat": "OK", "response": {"result": "allow", "status": "allow", "status_msg": "Success. Logging you in..."}}"#
+                }
+            };
+            common::respond(200, body)(request)
+        },
+    )
+}
+
+fn request() -> AuthRequest {
+    AuthRequest::new(
+        User::username("jdoe"),
+        AuthRequestFactor::Push {
+            device: "auto".into(),
+            r#type: None,
+            display_username: None,
+            push_info: None,
+        },
+    )
+}
+
+#[tokio::test]
+async fn auth_start_pending() {
+    let start = client(PENDING).auth_start(request()).await.unwrap();
+    assert_eq!(start.txid(), Some("45f7c92b-f45f-4862-8545-e0f58e78075a"));
+}
+
+#[tokio::test]
+async fn auth_start_immediate() {
+    match client(IMMEDIATE).auth_start(request()).await.unwrap() {
+        AuthStart::Immediate(status) => assert_eq!(status.result, AuthResult::Allow),
+        start => panic!("expected an immediate result, got {start:?}"),
+    }
+}
+
+#[tokio::test]
+async fn auth_reports_immediate_result() {
+    let err = client(IMMEDIATE).auth(request()).await.unwrap_err();
+    assert!(matches!(err, Error::AuthCompleted(status) if status.ready() == Some(true)));
+}
+
+#[tokio::test]
+async fn auth_wait_accepts_both_shapes() {
+    assert!(client(PENDING).auth_wait(request()).await.unwrap());
+    assert!(client(IMMEDIATE).auth_wait(request()).await.unwrap());
+}
+
+#[test]
+fn client_parameters_apply_hooks() {
+    let client = common::builder()
+        .normalize_username(|username| username.to_uppercase())
+        .display_username(|_| Some("Jane Doe".into()))
+        .build()
+        .unwrap();
+
+    let parameters = client.auth_parameters(&request()).unwrap();
+    assert_eq!(parameters["username"], "JDOE");
+    assert_eq!(parameters["display_username"], "Jane Doe");
+    assert_eq!(request().parameters().unwrap()["username"], "jdoe");
+
+    let preauth = PreauthRequest::new(User::username("jdoe"));
+    assert_eq!(
+        client.preauth_parameters(&preauth).unwrap()["username"],
+        "JDOE"
+    );
+}
+
+#[tokio::test]
+async fn query_extras_are_refused_for_post() {
+    let context = RequestContext::new("req-1").with_query("route", "eu");
+    let err = client(PENDING)
+        .auth_with_context(request(), context)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::InvalidParameter { name: "query", .. }));
+}
+
+#[tokio::test]
+async fn query_extras_are_sent_unsigned() {
+    let client = common::client(|request: Request| -> Result<Response, reqwest::Error> {
+        assert_eq!(request.url().path(), "/auth/v2/auth_status");
+        assert_eq!(request.url().query(), Some("txid=abc&route=eu"));
+        assert_eq!(request.headers()["X-Request-Id"], "req-1");
+
+        // Signed as if the extra pair was not there
+        let date = request.headers()["Date"].to_str().unwrap();
+        let date = DateTime::parse_from_rfc2822(date).unwrap().into();
+        let canonical = signing::canonical_string(
+            "GET",
+            common::API_HOST,
+            "/auth/v2/auth_status",
+            &date,
+            "txid=abc",
+        );
+        assert!(!canonical.contains("route"));
+        let signature = signing::sign(
+            "GET",
+            common::API_HOST,
+            "/auth/v2/auth_status",
+            &date,
+            "txid=abc",
+            common::SKEY,
+        );
+        let expected = signing::basic_authorization(common::IKEY, &signature);
+        assert_eq!(request.headers()["Authorization"], expected.as_str());
+
+        common::respond(
+            200,
+            r#"{"stat": "OK", "response": {"result": "allow", "status": "allow", "status_msg": "Success. Logging you in..."}}"#,
+        )(request)
+    });
+
+    let context = RequestContext::new("req-1").with_query("route", "eu");
+    let status = client
+        .auth_status_with_context("abc", context)
+        .await
+        .unwrap();
+    assert_eq!(status.result, AuthResult::Allow);
+}
+
+/// Answers pushes to `DPDENY` with a txid that gets denied, and pushes to
+/// any other device with one that gets approved.
+fn race(request: Request) -> Result<Response, reqwest::Error> {
+    let body = match request.url().path() {
+        "/auth/v2/auth" => {
+            let form = std::str::from_utf8(request.body().unwrap().as_bytes().unwrap()).unwrap();
+            if form.contains("device=DPDENY") {
+                r#"{"stat": "OK", "response": {"txid": "deny"}}"#
+            } else {
+                r#"{"stat": "OK", "response": {"txid": "allow"}}"#
+            }
+        }
+        _ if request.url().query().unwrap().contains("txid=deny") => {
+            r#"{"stat": "OK", "response": {"result": "deny", "status": "deny", "status_msg": "Login request denied."}}"#
+        }
+        _ => {
+            r#"{"stat": "OK", "response": {"result": "allow", "status": "allow", "status_msg": "Success. Logging you in..."}}"#
+        }
+    };
+    Ok(http::Response::builder()
+        .status(200)
+        .body(body)
+        .unwrap()
+        .into())
+}
+
+fn push_device(id: &str) -> Device {
+    serde_json::from_value(serde_json::json!({
+        "capabilities": ["auto", "push"],
+        "device": id,
+        "display_name": null,
+        "name": "",
+        "number": "",
+        "sms_nextcode": null,
+        "type": "phone"
+    }))
+    .unwrap()
+}
+
+#[tokio::test]
+async fn auth_race_returns_first_approval() {
+    let client = common::client(race);
+
+    let (deny, allow) = (push_device("DPDENY"), push_device("DPALLOW"));
+    let status = client
+        .auth_race(User::username("jdoe"), vec![&deny, &allow])
+        .await
+        .unwrap();
+    assert_eq!(status.result, AuthResult::Allow);
+
+    let status = client
+        .auth_race(User::username("jdoe"), vec![&deny])
+        .await
+        .unwrap();
+    assert_eq!(status.result, AuthResult::Deny);
+}
+
+#[test]
+fn status_into_result() {
+    let status = |result: &str, status: &str| -> AuthStatusResponse {
+        serde_json::from_value(serde_json::json!({
+            "result": result,
+            "status": status,
+            "status_msg": "",
+            "trusted_device_token": "token"
+        }))
+        .unwrap()
+    };
+
+    let allowed = status("allow", "allow").into_result().unwrap();
+    assert_eq!(allowed.trusted_device_token.as_deref(), Some("token"));
+    assert!(matches!(
+        status("deny", "fraud").into_result(),
+        Err(Error::AuthDenied {
+            reason: Some(DenyReason::Fraud),
+            ..
+        })
+    ));
+    assert!(matches!(
+        status("deny", "push_failed").into_result(),
+        Err(Error::AuthDenied { reason: None, .. })
+    ));
+    assert!(matches!(
+        status("waiting", "pushed").into_result(),
+        Err(Error::StillWaiting)
+    ));
+}
@@ -0,0 +1,34 @@
+#![cfg(feature = "capture-bodies")]
+
+mod common;
+
+use duo_auth::{
+    errors::Error,
+    types::{PreauthRequest, User},
+};
+
+#[tokio::test]
+async fn captured_request_is_redacted() {
+    let client = common::builder()
+        .capture_bodies(true)
+        .sensitive_parameters(&["username"])
+        .executor(common::respond(
+            400,
+            r#"{"stat": "FAIL", "code": 40002, "message": "Invalid request parameters"}"#,
+        ))
+        .build()
+        .unwrap();
+
+    let err = client
+        .preauth(PreauthRequest::new(User::username("jdoe")))
+        .await
+        .unwrap_err();
+    let Error::ApiRequestFailed {
+        captured: Some(captured),
+        ..
+    } = err
+    else {
+        panic!("expected captured bodies, got {err:?}");
+    };
+    assert_eq!(captured.request, "username=***");
+}
@@ -0,0 +1,37 @@
+//! Fixtures shared by the integration tests.
+#![allow(dead_code)]
+
+use duo_auth::{DuoClient, DuoClientBuilder};
+use reqwest::{Request, Response};
+
+pub const API_DOMAIN: &str = "https://api-abcdef12.duosecurity.com";
+pub const API_HOST: &str = "api-abcdef12.duosecurity.com";
+pub const IKEY: &str = "DIWJ8X6AEYOR5OMC6TQ1";
+pub const SKEY: &str = "Zh5eGmUq9zpfQnyUIu5OL9iWoMMv5ZNmk3zLJ4Ep";
+
+/// A builder with the test credentials, for options the fixture lacks.
+pub fn builder() -> DuoClientBuilder {
+    DuoClient::builder(API_DOMAIN, IKEY, SKEY)
+}
+
+/// A client handing every request to `executor` instead of sending it.
+pub fn client<F>(executor: F) -> DuoClient
+where
+    F: FnMut(Request) -> Result<Response, reqwest::Error> + Send + 'static,
+{
+    builder().executor(executor).build().unwrap()
+}
+
+/// An executor answering every request with `status` and `body`.
+pub fn respond(
+    status: u16,
+    body: &'static str,
+) -> impl FnMut(Request) -> Result<Response, reqwest::Error> + Send + 'static {
+    move |_| {
+        Ok(http::Response::builder()
+            .status(status)
+            .body(body)
+            .unwrap()
+            .into())
+    }
+}
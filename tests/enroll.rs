@@ -0,0 +1,38 @@
+use duo_auth::types::{ActivationCode, EnrollResponse};
+
+fn enroll_response(activation_code: &str) -> EnrollResponse {
+    EnrollResponse {
+        activation_barcode: "https://api-abcdef12.duosecurity.com/frame/qr?value=duo%3A%2F%2F7Xg8vA8LpOTJBQ2vPWJn-YXBpLWFiY2RlZjEyLmR1b3NlY3VyaXR5LmNvbQ".into(),
+        activation_code: activation_code.into(),
+        expiration: 1_700_000_000,
+        user_id: "DU94LZ4KQNHWQ1C4OIDT".into(),
+        username: "alice".into(),
+    }
+}
+
+#[test]
+fn parses_activation_code() {
+    let response =
+        enroll_response("duo://7Xg8vA8LpOTJBQ2vPWJn-YXBpLWFiY2RlZjEyLmR1b3NlY3VyaXR5LmNvbQ");
+
+    assert_eq!(
+        response.parsed_activation(),
+        Some(ActivationCode {
+            code: "7Xg8vA8LpOTJBQ2vPWJn".into(),
+            api_host: "api-abcdef12.duosecurity.com".into(),
+        })
+    );
+}
+
+#[test]
+fn rejects_unexpected_activation_code() {
+    assert_eq!(
+        enroll_response("7Xg8vA8LpOTJBQ2vPWJn").parsed_activation(),
+        None
+    );
+    assert_eq!(
+        enroll_response("duo://7Xg8vA8LpOTJBQ2vPWJn").parsed_activation(),
+        None
+    );
+    assert_eq!(enroll_response("duo://-YXBp").parsed_activation(), None);
+}
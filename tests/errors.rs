@@ -0,0 +1,43 @@
+use std::time::Duration;
+
+use duo_auth::errors::Error;
+
+#[test]
+fn http_status_hints() {
+    let api_error = |code| Error::ApiRequestFailed {
+        code,
+        message: String::new(),
+        message_detail: None,
+        captured: None,
+    };
+
+    let cases = [
+        (
+            Error::InvalidCredentials {
+                code: 40103,
+                message: "Invalid signature in request credentials".into(),
+            },
+            401,
+        ),
+        (Error::RateLimited { retry_after: None }, 429),
+        (
+            Error::InvalidParameter {
+                name: "passcode",
+                reason: "empty".into(),
+            },
+            400,
+        ),
+        (Error::DeadlineExceeded, 504),
+        (
+            Error::CircuitOpen {
+                retry_after: Duration::from_secs(1),
+            },
+            503,
+        ),
+        (api_error(40002), 400),
+        (api_error(50000), 502),
+    ];
+    for (err, status) in cases {
+        assert_eq!(err.http_status_hint(), status, "{err:?}");
+    }
+}
@@ -0,0 +1,57 @@
+mod common;
+
+use std::time::Duration;
+
+use duo_auth::{
+    errors::Error,
+    types::{Failmode, PreauthRequest, PreauthResponse, User},
+    DuoClient,
+};
+
+/// A client whose every request gets a 503 back, with a breaker tripping
+/// after two of them.
+fn client() -> DuoClient {
+    common::builder()
+        .circuit_breaker(2, Duration::from_secs(60))
+        .executor(common::respond(503, "Service Unavailable"))
+        .build()
+        .unwrap()
+}
+
+fn request() -> PreauthRequest {
+    PreauthRequest::new(User::username("jdoe"))
+}
+
+#[tokio::test]
+async fn circuit_breaker_trips_after_threshold() {
+    let client = client();
+    for _ in 0..2 {
+        let err = client.preauth(request()).await.unwrap_err();
+        assert!(matches!(err, Error::ServiceUnavailable { status: 503, .. }));
+    }
+
+    let err = client.preauth(request()).await.unwrap_err();
+    assert!(matches!(err, Error::CircuitOpen { retry_after } if retry_after > Duration::ZERO));
+    assert!(err.is_unavailable());
+}
+
+#[tokio::test]
+async fn fails_open_while_duo_is_unavailable() {
+    let client = client();
+
+    // Twice on the 503s, then twice on the tripped breaker
+    for _ in 0..4 {
+        let outcome = client
+            .preauth_with_failmode(request(), Failmode::Open)
+            .await
+            .unwrap();
+        assert!(outcome.failmode_allow);
+        assert!(matches!(outcome.response, PreauthResponse::Allow { .. }));
+    }
+
+    let err = client
+        .preauth_with_failmode(request(), Failmode::Closed)
+        .await
+        .unwrap_err();
+    assert!(matches!(err, Error::CircuitOpen { .. }));
+}
@@ -0,0 +1,43 @@
+use duo_auth::request::{Parameters, SENSITIVE_PARAMETERS};
+
+#[test]
+fn serialization_is_canonical() {
+    let mut parameters = Parameters::default();
+    parameters.set("username", "alice smith@example.com");
+    parameters.set("factor", "push");
+    parameters.set("device", "auto");
+    parameters.set("pushinfo", "from=login&domain=example.com");
+    parameters.set("display_username", "Älice ~ 100%");
+
+    assert!(parameters.is_canonical());
+    assert!(parameters
+        .serialize()
+        .starts_with("device=auto&display_username="));
+}
+
+#[test]
+fn resync_codes_are_redacted() {
+    let mut parameters = Parameters::default();
+    for name in ["code1", "code2", "code3"] {
+        parameters.set(name, "123456");
+    }
+
+    assert!(parameters.redacted().values().all(|value| value == "***"));
+}
+
+#[test]
+fn redaction_honours_the_sensitive_set() {
+    let mut parameters = Parameters::default();
+    parameters.set("passcode", "123456");
+    parameters.set("pushinfo", "ip=10.0.0.1");
+
+    assert_eq!(
+        parameters.serialize_redacted_with(SENSITIVE_PARAMETERS),
+        "passcode=***&pushinfo=ip%3D10.0.0.1"
+    );
+    assert_eq!(
+        parameters.serialize_redacted_with(&["pushinfo"]),
+        "passcode=123456&pushinfo=***"
+    );
+    assert_eq!(parameters.redacted_with(&["pushinfo"])["pushinfo"], "***");
+}
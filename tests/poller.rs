@@ -0,0 +1,141 @@
+mod common;
+
+use std::time::Duration;
+
+use duo_auth::{
+    transaction::PollStrategy,
+    types::{AuthRequest, AuthRequestFactor, User},
+    DuoClient,
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+/// Transactions of users below this number never finish during a test.
+const SLOW_USERS: usize = 16;
+
+/// Serves auths whose txid is the username. Transactions of the first
+/// `SLOW_USERS` users are either held open, like a long-poll that is not
+/// answered, or reported as waiting right away.
+async fn serve(listener: TcpListener, hold: bool) {
+    loop {
+        let (stream, _) = listener.accept().await.unwrap();
+        tokio::spawn(respond(stream, hold));
+    }
+}
+
+async fn respond(mut stream: TcpStream, hold: bool) {
+    let mut buf = Vec::new();
+    let mut chunk = [0; 4096];
+    let (head, body) = loop {
+        let n = stream.read(&mut chunk).await.unwrap();
+        if n == 0 {
+            return;
+        }
+        buf.extend_from_slice(&chunk[..n]);
+        let Some(end) = buf.windows(4).position(|window| window == b"\r\n\r\n") else {
+            continue;
+        };
+        let head = String::from_utf8_lossy(&buf[..end]).into_owned();
+        let length: usize = head
+            .lines()
+            .find_map(|line| {
+                line.to_ascii_lowercase()
+                    .strip_prefix("content-length:")
+                    .map(|v| v.trim().parse().unwrap())
+            })
+            .unwrap_or(0);
+        while buf.len() < end + 4 + length {
+            let n = stream.read(&mut chunk).await.unwrap();
+            buf.extend_from_slice(&chunk[..n]);
+        }
+        break (head, String::from_utf8_lossy(&buf[end + 4..]).into_owned());
+    };
+
+    let target = head.split(' ').nth(1).unwrap();
+    let value = |text: &str, name: &str| -> String {
+        let start = text.find(name).unwrap() + name.len();
+        text[start..]
+            .chars()
+            .take_while(|c| c.is_ascii_alphanumeric())
+            .collect()
+    };
+
+    let response = if target.starts_with("/auth/v2/auth?") || target == "/auth/v2/auth" {
+        format!(r#"{{"txid": "{}"}}"#, value(&body, "username="))
+    } else {
+        let txid = value(target, "txid=");
+        let slow = txid[4..].parse::<usize>().unwrap() < SLOW_USERS;
+        match (slow, hold) {
+            (true, true) => {
+                tokio::time::sleep(Duration::from_secs(60)).await;
+                return;
+            }
+            (true, false) => {
+                r#"{"result": "waiting", "status": "pushed", "status_msg": "Pushed a login request to your device..."}"#.to_string()
+            }
+            (false, _) => {
+                r#"{"result": "allow", "status": "allow", "status_msg": "Success. Logging you in..."}"#.to_string()
+            }
+        }
+    };
+
+    let body = format!(r#"{{"stat": "OK", "response": {response}}}"#);
+    let reply = format!(
+        "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    let _ = stream.write_all(reply.as_bytes()).await;
+}
+
+/// Starts `auth_wait` for `SLOW_USERS` slow users and a few more, and checks
+/// that the others finish while the slow ones are still pending.
+async fn others_finish(hold: bool, poll_strategy: PollStrategy) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(serve(listener, hold));
+
+    let client = DuoClient::builder(format!("http://{addr}"), common::IKEY, common::SKEY)
+        .poll_strategy(poll_strategy)
+        .build()
+        .unwrap();
+
+    let auth_wait = |i: usize| {
+        let client = client.clone();
+        tokio::spawn(async move {
+            let request = AuthRequest::new(
+                User::username(format!("user{i}")),
+                AuthRequestFactor::auto(),
+            );
+            client.auth_wait(request).await.unwrap()
+        })
+    };
+
+    let slow: Vec<_> = (0..SLOW_USERS).map(auth_wait).collect();
+    // Let the slow transactions take their turns first
+    tokio::time::sleep(Duration::from_millis(200)).await;
+    let others: Vec<_> = (SLOW_USERS..SLOW_USERS + 4).map(auth_wait).collect();
+
+    tokio::time::timeout(Duration::from_secs(5), async {
+        for other in others {
+            assert!(other.await.unwrap());
+        }
+    })
+    .await
+    .expect("waits stalled behind slow transactions");
+
+    for wait in slow {
+        wait.abort();
+    }
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn held_long_polls_do_not_stall_others() {
+    others_finish(true, PollStrategy::default()).await;
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn poll_delays_do_not_stall_others() {
+    others_finish(false, PollStrategy::Interval(Duration::from_secs(30))).await;
+}
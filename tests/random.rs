@@ -0,0 +1,16 @@
+use duo_auth::random::{RandomSource, SeededRandom};
+
+#[test]
+fn seeded_random_is_reproducible() {
+    let draw = |seed| {
+        let random = SeededRandom::new(seed);
+        (0..100)
+            .map(|i| random.sample(&i.to_string()))
+            .collect::<Vec<_>>()
+    };
+
+    let samples = draw(42);
+    assert_eq!(samples, draw(42));
+    assert_ne!(samples, draw(43));
+    assert!(samples.iter().all(|sample| (0.0..=1.0).contains(sample)));
+}
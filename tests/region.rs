@@ -0,0 +1,11 @@
+use duo_auth::DuoRegion;
+
+#[test]
+fn regions_have_distinct_hosts() {
+    let commercial = DuoRegion::Commercial.api_domain("abcdef12").unwrap();
+    let federal = DuoRegion::Federal.api_domain("api-ABCDEF12").unwrap();
+
+    assert_eq!(commercial, "https://api-abcdef12.duosecurity.com");
+    assert_eq!(federal, "https://api-abcdef12.duofederal.com");
+    assert!(DuoRegion::Commercial.api_domain("api-").is_err());
+}
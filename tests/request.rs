@@ -0,0 +1,156 @@
+mod common;
+
+use duo_auth::{
+    errors::Error,
+    request::{DuoRequest, Parameters},
+    types::{AuthRequest, AuthRequestFactor, Passcode, User},
+};
+use reqwest::{tls, Method, Request, Response, Url};
+
+#[test]
+fn hostless_url_is_an_error() {
+    let request = DuoRequest::new(
+        Url::parse("unix:/run/duo.sock").unwrap(),
+        Method::GET,
+        "/auth/v2/check",
+        Parameters::default(),
+    );
+
+    let err = request
+        .authorization_value(common::IKEY, common::SKEY)
+        .unwrap_err();
+    assert!(matches!(
+        err.downcast_ref::<Error>(),
+        Some(Error::InvalidApiDomain { .. })
+    ));
+}
+
+#[test]
+fn passcode_keeps_leading_zeros() {
+    let passcode = Passcode::from_digits("012345").unwrap();
+    assert_eq!(passcode.as_str(), "012345");
+    assert!(Passcode::from_digits("12 345").is_err());
+    assert!(Passcode::from_digits("").is_err());
+
+    let request = AuthRequest::new(
+        User::username("jdoe"),
+        AuthRequestFactor::passcode(passcode),
+    );
+    assert!(
+        matches!(&request.factor, AuthRequestFactor::Passcode { passcode } if passcode == "012345")
+    );
+    request.validate().unwrap();
+}
+
+#[test]
+fn hardware_token_otp_is_valid() {
+    let request = AuthRequest::new(
+        User::username("jdoe"),
+        AuthRequestFactor::Passcode {
+            passcode: "cccccckdvvulgjvtkjdhtlrbjjctggdihuevikehtlil".into(),
+        },
+    );
+    request.validate().unwrap();
+}
+
+#[test]
+fn tls_1_3_is_rejected_up_front() {
+    let result = common::builder()
+        .min_tls_version(tls::Version::TLS_1_3)
+        .build();
+    assert!(matches!(
+        result,
+        Err(Error::InvalidParameter {
+            name: "min_tls_version",
+            ..
+        })
+    ));
+    common::builder()
+        .min_tls_version(tls::Version::TLS_1_2)
+        .build()
+        .unwrap();
+}
+
+#[tokio::test]
+async fn invalid_requests_are_rejected() {
+    let push = |device: &str| AuthRequestFactor::Push {
+        device: device.into(),
+        r#type: None,
+        display_username: None,
+        push_info: None,
+    };
+    let auto = AuthRequestFactor::Auto {
+        device: Some(String::new()),
+        r#type: None,
+        display_username: None,
+        push_info: None,
+    };
+    let jdoe = || User::username("jdoe");
+    let with = |ipaddr: Option<&str>, hostname: Option<&str>| {
+        let mut request = AuthRequest::new(jdoe(), push("auto"));
+        request.ipaddr = ipaddr.map(String::from);
+        request.hostname = hostname.map(String::from);
+        request
+    };
+
+    let cases = [
+        (AuthRequest::new(jdoe(), auto), "device"),
+        (AuthRequest::new(jdoe(), push("")), "device"),
+        (
+            AuthRequest::new(
+                jdoe(),
+                AuthRequestFactor::Phone {
+                    device: String::new().into(),
+                },
+            ),
+            "device",
+        ),
+        (
+            AuthRequest::new(
+                jdoe(),
+                AuthRequestFactor::Sms {
+                    device: String::new().into(),
+                },
+            ),
+            "device",
+        ),
+        (
+            AuthRequest::new(
+                jdoe(),
+                AuthRequestFactor::Passcode {
+                    passcode: String::new(),
+                },
+            ),
+            "passcode",
+        ),
+        (
+            AuthRequest::new(User::username(""), push("auto")),
+            "username",
+        ),
+        (AuthRequest::new(User::user_id(""), push("auto")), "user_id"),
+        (with(Some("10.0.0.300"), None), "ipaddr"),
+        (with(None, Some("vpn example.com")), "hostname"),
+    ];
+
+    // Nothing may be sent for an invalid request
+    let client = common::client(|request: Request| -> Result<Response, reqwest::Error> {
+        panic!("invalid request was sent: {}", request.url())
+    });
+
+    for (request, expected) in cases {
+        assert!(
+            matches!(
+                request.validate(),
+                Err(Error::InvalidParameter { name, .. }) if name == expected
+            ),
+            "{request:?}"
+        );
+        assert!(
+            matches!(
+                client.auth(request).await,
+                Err(Error::InvalidParameter { name, .. }) if name == expected
+            ),
+            "{expected}"
+        );
+    }
+}
@@ -0,0 +1,75 @@
+mod common;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::DateTime;
+use duo_auth::{credentials::Credentials, signing, DuoClient};
+use reqwest::{Request, Response};
+
+fn credentials(generation: usize) -> Credentials {
+    Credentials::new(
+        format!("DIWJ8X6AEYOR5OMC{generation:04}"),
+        format!("Zh5eGmUq9zpfQnyUIu5OL9iWoMMv5ZNmk3zLJ{generation:04}"),
+    )
+}
+
+/// Answers `ping` if the request was signed with the skey belonging to its
+/// ikey, and fails it otherwise.
+fn verify(request: Request) -> Result<Response, reqwest::Error> {
+    let authorization = request.headers()["Authorization"].to_str().unwrap();
+    let decoded = STANDARD
+        .decode(authorization.strip_prefix("Basic ").unwrap())
+        .unwrap();
+    let (ikey, signature) = std::str::from_utf8(&decoded)
+        .unwrap()
+        .split_once(':')
+        .map(|(ikey, signature)| (ikey.to_string(), signature.to_string()))
+        .unwrap();
+
+    let generation: usize = ikey[ikey.len() - 4..].parse().unwrap();
+    let date = request.headers()["Date"].to_str().unwrap();
+    let expected = signing::sign(
+        request.method().as_str(),
+        request.url().host_str().unwrap(),
+        request.url().path(),
+        &DateTime::parse_from_rfc2822(date).unwrap().into(),
+        request.url().query().unwrap_or_default(),
+        &credentials(generation).skey,
+    );
+
+    let body = if signature == expected {
+        r#"{"stat": "OK", "response": {"time": 1700000000}}"#
+    } else {
+        r#"{"stat": "FAIL", "code": 40103, "message": "Invalid signature in request credentials"}"#
+    };
+
+    Ok(http::Response::builder()
+        .status(200)
+        .body(body)
+        .unwrap()
+        .into())
+}
+
+#[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+async fn rotation_never_mixes_keys() {
+    let initial = credentials(0);
+    let client = DuoClient::builder(common::API_DOMAIN, initial.ikey, initial.skey)
+        .executor(verify)
+        .build()
+        .unwrap();
+
+    let requests: Vec<_> = (0..500)
+        .map(|_| {
+            let client = client.clone();
+            tokio::spawn(async move { client.ping().await })
+        })
+        .collect();
+
+    for generation in 1..=200 {
+        client.rotate_credentials(credentials(generation)).await;
+        tokio::task::yield_now().await;
+    }
+
+    for request in requests {
+        request.await.unwrap().unwrap();
+    }
+}
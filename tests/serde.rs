@@ -0,0 +1,165 @@
+use duo_auth::{
+    errors::Error,
+    response::DuoResponse,
+    types::{Device, PreauthOutcome, PreauthResponse},
+};
+use serde_json::json;
+
+#[test]
+fn preauth_auth_round_trip() {
+    let original = json!({
+        "result": "auth",
+        "devices": [
+            {
+                "capabilities": ["auto", "push", "sms", "phone", "mobile_otp"],
+                "device": "DPFZRS9FB0D46QFTM891",
+                "display_name": "iOS (XXX-XXX-0100)",
+                "name": "iPhone",
+                "number": "XXX-XXX-0100",
+                "sms_nextcode": "1",
+                "type": "phone"
+            },
+            {
+                "capabilities": null,
+                "device": "DHEKH0JJIYC1LX3AZWO4",
+                "display_name": "Token (123456)",
+                "name": "",
+                "number": "",
+                "sms_nextcode": null,
+                "type": "token"
+            }
+        ]
+    });
+
+    let response: PreauthResponse = serde_json::from_value(original.clone()).unwrap();
+    match &response {
+        PreauthResponse::Auth { devices } => {
+            assert_eq!(devices.len(), 2);
+            assert_eq!(devices[1].name, None);
+        }
+        other => panic!("unexpected response: {other:?}"),
+    }
+
+    assert_eq!(serde_json::to_value(&response).unwrap(), original);
+}
+
+#[test]
+fn preauth_device_state_fields() {
+    let response: PreauthResponse = serde_json::from_value(json!({
+        "result": "auth",
+        "devices": [
+            {
+                "capabilities": ["sms"],
+                "device": "DPFZRS9FB0D46QFTM891",
+                "name": "",
+                "number": "XXX-XXX-0100",
+                "sms_nextcode": "3",
+                "remaining_passcodes": 7,
+                "locked_out": true,
+                "type": "phone"
+            }
+        ]
+    }))
+    .unwrap();
+
+    match response {
+        PreauthResponse::Auth { devices } => {
+            assert_eq!(devices[0].remaining_passcodes, Some(7));
+            assert_eq!(devices[0].locked_out, Some(true));
+        }
+        other => panic!("unexpected response: {other:?}"),
+    }
+}
+
+#[test]
+fn preauth_outcome_round_trip() {
+    let outcome = PreauthOutcome {
+        response: PreauthResponse::Allow {
+            status_msg: Some("Allowing unknown user".into()),
+            trusted_device_token: None,
+            trusted_device_token_expiration: None,
+        },
+        failmode_allow: false,
+    };
+
+    let serialized = serde_json::to_string(&outcome).unwrap();
+    assert_eq!(
+        serde_json::from_str::<PreauthOutcome>(&serialized).unwrap(),
+        outcome
+    );
+}
+
+#[test]
+fn preauth_auth_without_devices() {
+    let response: PreauthResponse = serde_json::from_value(json!({
+        "result": "auth",
+        "devices": []
+    }))
+    .unwrap();
+
+    assert!(response.is_auth_without_devices());
+    assert!(!response.has_usable_device());
+}
+
+#[test]
+fn paginated_envelope() {
+    let response: DuoResponse<Vec<u64>> = serde_json::from_value(json!({
+        "stat": "OK",
+        "response": [1, 2],
+        "metadata": {"next_offset": 2, "total_objects": 5}
+    }))
+    .unwrap();
+    let page = response.into_page().unwrap();
+    assert_eq!(page.items, [1, 2]);
+    assert_eq!(page.next_offset(), Some(2));
+
+    let response: DuoResponse<Vec<u64>> = serde_json::from_value(json!({
+        "stat": "OK",
+        "response": [3]
+    }))
+    .unwrap();
+    let page = response.into_page().unwrap();
+    assert!(page.metadata.is_none());
+    assert_eq!(page.next_offset(), None);
+}
+
+#[test]
+fn unexpected_stat() {
+    let response: DuoResponse<Vec<u64>> = serde_json::from_value(json!({
+        "stat": "MAINTENANCE",
+        "message": "Back soon"
+    }))
+    .unwrap();
+    assert!(matches!(&response, DuoResponse::Unexpected(stat) if stat == "MAINTENANCE"));
+    assert!(matches!(
+        response.into_page(),
+        Err(Error::UnexpectedStat { stat }) if stat == "MAINTENANCE"
+    ));
+}
+
+#[test]
+fn device_label_fallbacks() {
+    let device = |display_name: Option<&str>, name: &str, number: &str| -> Device {
+        serde_json::from_value(json!({
+            "capabilities": ["sms"],
+            "device": "DPFZRS9FB0D46QFTM891",
+            "display_name": display_name,
+            "name": name,
+            "number": number,
+            "type": "phone"
+        }))
+        .unwrap()
+    };
+
+    assert_eq!(
+        device(Some("Work phone"), "iPhone", "+15555550100").label(),
+        "Work phone"
+    );
+    assert_eq!(
+        device(Some(" "), "iPhone", "+15555550100").label(),
+        "iPhone"
+    );
+    assert_eq!(device(None, "", "+15555550100").label(), "+15555550100");
+    assert_eq!(device(None, "", "XXX-XXX-0100").label(), "XXX-XXX-0100");
+    assert_eq!(device(None, "", "").label(), "DPFZRS9FB0D46QFTM891");
+}
@@ -0,0 +1,36 @@
+mod common;
+
+use chrono::DateTime;
+use common::{API_HOST, IKEY, SKEY};
+use duo_auth::{signing, DuoClient};
+use reqwest::{Request, Response};
+
+#[tokio::test]
+async fn synthetic_host_signs_with_signing_host() {
+    let client = DuoClient::builder("http://duo.internal", IKEY, SKEY)
+        .signing_host(API_HOST)
+        .executor(|request: Request| -> Result<Response, reqwest::Error> {
+            let date = request.headers()["Date"].to_str().unwrap();
+            let signature = signing::sign(
+                request.method().as_str(),
+                API_HOST,
+                request.url().path(),
+                &DateTime::parse_from_rfc2822(date).unwrap().into(),
+                request.url().query().unwrap_or_default(),
+                SKEY,
+            );
+            assert_eq!(request.url().host_str(), Some("duo.internal"));
+            let expected = signing::basic_authorization(IKEY, &signature);
+            assert_eq!(request.headers()["Authorization"], expected.as_str());
+
+            Ok(http::Response::builder()
+                .status(200)
+                .body(r#"{"stat": "OK", "response": {"time": 1700000000}}"#)
+                .unwrap()
+                .into())
+        })
+        .build()
+        .unwrap();
+
+    client.check().await.unwrap();
+}
@@ -0,0 +1,46 @@
+mod common;
+
+use duo_auth::{request::DEFAULT_USER_AGENT, DuoClient};
+use reqwest::{Request, Response};
+
+const PING: &str = r#"{"stat": "OK", "response": {"time": 1700000000}}"#;
+
+/// A client asserting that every request carries `expected` as User-Agent.
+fn client(user_agent: Option<&str>, expected: Option<&'static str>) -> DuoClient {
+    common::builder()
+        .user_agent(user_agent.map(String::from))
+        .executor(
+            move |request: Request| -> Result<Response, reqwest::Error> {
+                let sent = request
+                    .headers()
+                    .get("User-Agent")
+                    .map(|value| value.to_str().unwrap());
+                assert_eq!(sent, expected);
+                common::respond(200, PING)(request)
+            },
+        )
+        .build()
+        .unwrap()
+}
+
+#[tokio::test]
+async fn user_agent_reaches_signed_requests() {
+    assert!(DEFAULT_USER_AGENT.starts_with("duo-auth-rs/"));
+
+    common::builder()
+        .executor(|request: Request| -> Result<Response, reqwest::Error> {
+            assert_eq!(request.headers()["User-Agent"], DEFAULT_USER_AGENT);
+            common::respond(200, PING)(request)
+        })
+        .build()
+        .unwrap()
+        .check()
+        .await
+        .unwrap();
+
+    client(Some("generic"), Some("generic"))
+        .check()
+        .await
+        .unwrap();
+    client(None, None).check().await.unwrap();
+}